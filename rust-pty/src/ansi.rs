@@ -0,0 +1,55 @@
+//! ansi.rs  —  escape-sequence filter behind `bun_pty_read_plain`
+
+/// Where the filter is inside an escape sequence. Kept between chunks, so a
+/// sequence split across two reads is still dropped whole.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum State {
+    #[default]
+    Ground,
+    Esc,                                        // after ESC
+    EscInter,                                   // ESC + intermediates, e.g. `ESC ( B`
+    Csi,                                        // `ESC [` params…, up to the final byte
+    Str,                                        // OSC / DCS / SOS / PM / APC body, up to BEL or ST
+    StrEsc,                                     // ESC inside a string: `\` ends it
+}
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+/// Removes CSI, OSC and the other ESC-introduced sequences from a byte stream,
+/// passing everything else (text, `\r`, `\n`, tabs…) through untouched.
+#[derive(Default)]
+pub(crate) struct Stripper {
+    state: State,
+}
+
+impl Stripper {
+    /// Filters one chunk; an unfinished sequence at its end is swallowed and its
+    /// remainder dropped from the next chunk.
+    pub(crate) fn strip(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len());
+        for &b in input {
+            if self.state == State::StrEsc && b != b'\\' {
+                self.state = State::Esc;                // any other ESC ends the string and starts anew
+            }
+            self.state = match (self.state, b) {
+                (State::StrEsc, _)                           => State::Ground,   // ST
+                (State::Str, BEL)                            => State::Ground,
+                (State::Str, ESC)                            => State::StrEsc,
+                (State::Str, _)                              => State::Str,
+                (_, ESC)                                     => State::Esc,
+                (State::Ground, _)                           => { out.push(b); State::Ground }
+                (_, CAN | SUB)                               => State::Ground,   // aborts the sequence
+                (State::Esc, b'[')                           => State::Csi,
+                (State::Esc, b']' | b'P' | b'X' | b'^' | b'_') => State::Str,
+                (State::Esc | State::EscInter, 0x20..=0x2f)  => State::EscInter,
+                (State::Csi, 0x40..=0x7e)                    => State::Ground,
+                (State::Csi, _)                              => State::Csi,
+                (State::Esc | State::EscInter, _)            => State::Ground,  // final byte
+            };
+        }
+        out
+    }
+}
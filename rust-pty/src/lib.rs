@@ -1,330 +1,4379 @@
 //! lib.rs  —  bun-pty backend (final fixed version)
 
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use crossbeam::channel::{bounded, select, unbounded, Receiver, SendTimeoutError, Sender};
 use portable_pty::{
-    native_pty_system, ChildKiller, CommandBuilder, MasterPty, PtySize, SlavePty,
+    native_pty_system, Child, ChildKiller, MasterPty, PtySize,
 };
+#[cfg(windows)]
+use portable_pty::CommandBuilder;
 use serde::{Deserialize, Serialize};
 use shell_words::split;                  // <-- NEW
+mod ansi;
+#[cfg(unix)]
+mod shared;
+#[cfg(test)]
+mod tests;
+
 use std::{
-    collections::HashMap,
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
     ffi::CStr,
-    io::{Read, Write},
-    os::raw::{c_char, c_int},
+    io::{self, Read, Write},
+    os::raw::{c_char, c_int, c_void},
     sync::{
-        atomic::{AtomicBool, AtomicI32, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
+#[cfg(unix)]
+use std::ffi::CString;
 
 /* ---------- constants ---------- */
 
 const SUCCESS: c_int      = 0;
+const NO_DATA: c_int      = 0;                  // read calls: nothing buffered yet, try again later
 const ERROR: c_int        = -1;
 const CHILD_EXITED: c_int = -2;
+const TIMEOUT: c_int      = -3;
+const EOF_REACHED: c_int  = -8;                  // output closed, child not reaped yet (`report_eof`)
+const MANUAL_READS: c_int = -9;                  // spawned with `manage_reads: false`; use `bun_pty_raw_read`
+
+/* spawn failures; details via `bun_pty_last_error` */
+const ERR_NOT_FOUND: c_int         = -4;
+const ERR_PERMISSION_DENIED: c_int = -5;
+const ERR_INVALID_JSON: c_int      = -6;
+const ERR_INVALID_CMDLINE: c_int   = -7;
+const ERR_HANDLE_IN_USE: c_int     = -10;
+const ERR_INVALID_CWD: c_int       = -11;
+const TOO_MANY_HANDLES: c_int      = -12;    // `bun_pty_set_max_handles` cap reached
+
+const IDLE_KILLED: c_int = -13;                  // `bun_pty_get_exit_code`: killed by the idle timeout
+const BUFFER_TOO_SMALL: c_int = -14;             // can't hold even one encoded unit (`bun_pty_read_encoded`)
+const PID_UNAVAILABLE: c_int = -15;              // the handle is fine, but its child's PID isn't known
+
+/* output encodings (bun_pty_read_encoded) */
+const ENCODING_RAW: c_int    = 0;
+const ENCODING_BASE64: c_int = 1;                // standard alphabet, padded
+const ENCODING_HEX: c_int    = 2;                // lowercase
+
+/* bun_pty_terminate outcomes */
+const TERMINATED: c_int  = 0;                    // exited within the grace period
+const FORCE_KILLED: c_int = 1;                   // still running after it, killed
+
+/* threading modes (bun_pty_set_threading_mode) */
+const THREADING_DEDICATED: c_int = 0;            // read-, write- and wait-thread per PTY
+const THREADING_SHARED: c_int    = 1;            // one poll loop plus a write pool for all (Unix)
 
 /* ---------- helpers ---------- */
 
+/// `Mutex::lock` that takes over a poisoned lock instead of panicking. A panic on some
+/// thread then costs at most that one call, not every later call on every handle.
+trait LockExt<T> {
+    fn locked(&self) -> MutexGuard<'_, T>;
+}
+impl<T> LockExt<T> for Mutex<T> {
+    fn locked(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
 fn debug(msg: &str) {
     if std::env::var("BUN_PTY_DEBUG").unwrap_or_default() == "1" {
         eprintln!("[rust-pty] {msg}");
     }
 }
 
-/* ---------- command struct ---------- */
+/// `debug` for one PTY, tagged with its `label` if it has one.
+fn debug_as(label: &str, msg: &str) {
+    if label.is_empty() { debug(msg) } else { debug(&format!("[{label}] {msg}")) }
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Command {
-    cmd: String,
-    args: Vec<String>,
-    env: HashMap<String, String>,
-    cwd: String,
+thread_local! {
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
 }
 
-impl Command {
-    fn from_cmdline(cmdline: &str, cwd: &str) -> Self {
-        let tokens = split(cmdline).unwrap_or_default();   // shell-accurate split
-        if tokens.is_empty() {
-            return Self {
-                cmd: String::new(),
-                args: Vec::new(),
-                env: HashMap::new(),
-                cwd: cwd.to_owned(),
+/// Stashes `e` for `bun_pty_last_error` and maps it to a stable error code; `label`
+/// is the spawn's `label` option, for the debug line.
+fn spawn_failed(label: &str, e: &(dyn std::error::Error + 'static)) -> c_int {
+    debug_as(label, &format!("spawn error: {e}"));
+    LAST_ERROR.with(|l| *l.borrow_mut() = e.to_string());
+
+    let mut cur = Some(e);
+    while let Some(err) = cur {
+        if err.is::<InvalidCwd>() { return ERR_INVALID_CWD; }
+        if err.is::<TooManyHandles>() { return TOO_MANY_HANDLES; }
+        if let Some(io) = err.downcast_ref::<io::Error>() {
+            return match io.kind() {
+                io::ErrorKind::NotFound         => ERR_NOT_FOUND,
+                io::ErrorKind::PermissionDenied => ERR_PERMISSION_DENIED,
+                _                               => ERROR,
             };
         }
+        cur = err.source();
+    }
+    ERROR
+}
 
-        let cmd  = tokens[0].clone();
-        let args = tokens[1..].to_vec();
+/// Reaps the child, returning `(exit_code, signal)`; `signal` is 0 for a normal exit.
+/// On Unix this waits on the raw status since portable-pty only keeps the signal's name.
+#[cfg(unix)]
+fn wait_child(child: &mut Box<dyn Child + Send + Sync>, pid: Option<u32>) -> Option<(i32, i32)> {
+    if let Some(pid) = pid {
+        let pid = pid as libc::pid_t;
+        let mut status = 0;
+        loop {
+            if unsafe { libc::waitpid(pid, &mut status, 0) } == pid {
+                if libc::WIFSIGNALED(status) {
+                    return Some((1, libc::WTERMSIG(status)));     // portable-pty reports 1 here
+                }
+                return Some((libc::WEXITSTATUS(status), 0));
+            }
+            if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted { break; }
+        }
+    }
+    child.wait().ok().map(|s| (s.exit_code() as i32, 0))
+}
 
-        let env = std::env::vars().collect();              // forward everything
+/// Windows exit codes are full 32-bit values (NTSTATUS crash codes like 0xC0000005
+/// among them); the cast keeps the bits, so `bun_pty_get_exit_code_u32` can undo it.
+#[cfg(windows)]
+fn wait_child(child: &mut Box<dyn Child + Send + Sync>, _pid: Option<u32>) -> Option<(i32, i32)> {
+    child.wait().ok().map(|s| (s.exit_code() as i32, 0))
+}
 
-        Self { cmd, args, env, cwd: cwd.to_owned() }
+/// Clears (raw) or sets (cooked) ICANON and ECHO on the PTY's line discipline.
+#[cfg(unix)]
+fn apply_raw_mode(fd: c_int, raw: bool) -> c_int {
+    let mut t: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut t) } != 0 { return ERROR; }
+    if raw { t.c_lflag &= !(libc::ICANON | libc::ECHO); } else { t.c_lflag |= libc::ICANON | libc::ECHO; }
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &t) } != 0 { return ERROR; }
+    SUCCESS
+}
+
+/// Background threads still running, so `bun_pty_shutdown` can wait them out, and
+/// the ones started, so it can then join them.
+static WORKERS: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+static JOINS: Mutex<Vec<thread::JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// `thread::spawn` for the per-PTY threads, counted in `WORKERS` while they run.
+fn spawn_worker(f: impl FnOnce() + Send + 'static) {
+    struct Done;
+    impl Drop for Done {
+        fn drop(&mut self) {
+            *WORKERS.0.locked() -= 1;
+            WORKERS.1.notify_all();
+        }
     }
+    *WORKERS.0.locked() += 1;
+    let done = Done;                                  // dropped with the closure if the thread never starts
+    let h = thread::spawn(move || { let _done = done; f() });
+    let mut joins = JOINS.locked();
+    joins.retain(|h| !h.is_finished());
+    joins.push(h);
+}
 
-    fn to_builder(&self) -> CommandBuilder {
-        let mut b = CommandBuilder::new(&self.cmd);
-        b.cwd(&self.cwd);
-        for a in &self.args {
-            b.arg(a);
+/// `write_all` that reports how far it got: (bytes written, whether all of it went out and flushed).
+fn write_counted(w: &mut dyn Write, data: &[u8]) -> (usize, bool) {
+    let mut done = 0;
+    while done < data.len() {
+        match w.write(&data[done..]) {
+            Ok(0)                                             => return (done, false),
+            Ok(n)                                             => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted  => {}
+            Err(_)                                            => return (done, false),
         }
-        for (k, v) in &self.env {
-            b.env(k, v);
+    }
+    (done, w.flush().is_ok())
+}
+
+/// Where in a message an acknowledged write's bytes are, and where to report them.
+type Ack = (usize, usize, Sender<c_int>);
+
+/// A queued message as it goes out: its bytes, with `coalesce` everything else queued
+/// behind it joined on, and the acknowledgements for the pieces of it.
+fn gather((mut data, len, ack): WriteMsg, rx_w: &Receiver<WriteMsg>, coalesce: bool) -> (Vec<u8>, Vec<Ack>) {
+    data.truncate(len);
+    let mut acks: Vec<Ack> = ack.map(|a| (0, len, a)).into_iter().collect();
+    if coalesce {
+        for (more, n, ack) in rx_w.try_iter() {
+            let start = data.len();
+            data.extend_from_slice(&more[..n]);
+            if let Some(a) = ack { acks.push((start, data.len(), a)); }
         }
-        b
     }
+    (data, acks)
 }
 
-/* ---------- async message channel ---------- */
+/// Tells each acknowledged write how much of it was among the `written` bytes; `ERROR`
+/// for one the failure came before.
+fn settle(acks: Vec<Ack>, written: usize, ok: bool) {
+    for (start, end, a) in acks {
+        let n = written.clamp(start, end) - start;
+        let _ = a.send(if !ok && written <= start { ERROR } else { n as c_int });
+    }
+}
 
-#[derive(Debug, PartialEq, Eq)]
-enum Msg {
-    Data(Vec<u8>),
-    End,
+/// Path of the slave side of the PTY whose master is `fd`.
+#[cfg(unix)]
+fn tty_name(fd: c_int) -> Option<std::path::PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    static PTSNAME: Mutex<()> = Mutex::new(());      // `ptsname` returns a shared static buffer
+    let _guard = PTSNAME.locked();
+    let name = unsafe { libc::ptsname(fd) };
+    if name.is_null() { return None; }
+    Some(std::ffi::OsStr::from_bytes(unsafe { CStr::from_ptr(name) }.to_bytes()).into())
 }
 
-struct Reader {
-    rx:   Receiver<Msg>,
-    done: AtomicBool,
+/// The parts of a passwd entry we use, read with the reentrant `getpwuid_r`.
+#[cfg(unix)]
+struct Passwd {
+    name:  CString,
+    gid:   libc::gid_t,
+    shell: String,
 }
-impl Reader {
-    fn new(rx: Receiver<Msg>) -> Self {
-        Self { rx, done: AtomicBool::new(false) }
+
+#[cfg(unix)]
+fn passwd(uid: libc::uid_t) -> Option<Passwd> {
+    let mut buf: Vec<c_char> = vec![0; 1024];
+    loop {
+        let mut pw: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        match unsafe { libc::getpwuid_r(uid, &mut pw, buf.as_mut_ptr(), buf.len(), &mut found) } {
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            0 if !found.is_null() && !pw.pw_name.is_null() => {
+                let shell = match pw.pw_shell.is_null() {
+                    true  => String::new(),
+                    false => unsafe { CStr::from_ptr(pw.pw_shell) }.to_string_lossy().into_owned(),
+                };
+                return Some(Passwd { name: unsafe { CStr::from_ptr(pw.pw_name) }.into(), gid: pw.pw_gid, shell });
+            }
+            _ => return None,
+        }
     }
+}
+
+/// The login shell in the current user's passwd entry.
+#[cfg(unix)]
+fn passwd_shell() -> Option<String> {
+    passwd(unsafe { libc::getuid() }).map(|pw| pw.shell).filter(|s| !s.is_empty())
+}
 
-    fn read(&self) -> Result<Msg, Box<dyn std::error::Error + Send + Sync>> {
-        if self.done.load(Ordering::Relaxed) {
-            return Ok(Msg::End);
+/// `user`'s groups with `gid` among them, as `initgroups` would set them; looked up here
+/// since the lookup isn't safe to do between fork and exec.
+#[cfg(unix)]
+fn group_list(user: &CStr, gid: libc::gid_t) -> io::Result<Vec<libc::gid_t>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut n = groups.len() as c_int;
+        if unsafe { libc::getgrouplist(user.as_ptr(), gid as _, groups.as_mut_ptr().cast(), &mut n) } != -1 {
+            groups.truncate(n as usize);
+            return Ok(groups);
         }
-        let mut msgs: Vec<_> = self.rx.try_iter().collect();
-        if msgs.iter().any(|m| matches!(m, Msg::End)) {
-            self.done.store(true, Ordering::Relaxed);
-            thread::sleep(Duration::from_millis(20));
-            msgs.extend(self.rx.try_iter());
-            msgs.retain(|m| !matches!(m, Msg::End));
-            if msgs.is_empty() { return Ok(Msg::End); }
+        if groups.len() >= 1 << 16 { return Err(io::Error::other("too many supplementary groups")); }
+        groups.resize((n as usize).max(groups.len() * 2), 0);
+    }
+}
+
+/// What the child switches to before exec: `uid`, `gid` and the supplementary groups.
+#[cfg(unix)]
+struct Credentials {
+    uid:    Option<libc::uid_t>,
+    gid:    Option<libc::gid_t>,
+    groups: Vec<libc::gid_t>,
+}
+
+/// Checks the `uid`/`gid` options before forking and looks up everything the child
+/// will need. A `uid` without a `gid` gets the primary group from that user's passwd
+/// entry, rather than keeping ours, and the child gets that user's supplementary
+/// groups; a bare `gid` gets no others. Only the ids change: `HOME`, `USER` and the
+/// rest of the environment are left to the caller.
+#[cfg(unix)]
+fn credentials(opts: &SpawnOptions) -> io::Result<Credentials> {
+    let pw = opts.uid.and_then(passwd);
+    let gid = match (opts.uid, opts.gid, &pw) {
+        (_, Some(g), _)          => Some(g),
+        (Some(_), None, Some(pw)) => Some(pw.gid),
+        (Some(u), None, None)    => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("uid {u} has no passwd entry to take a group from; set gid")));
         }
-        if msgs.is_empty() {
-            return Ok(Msg::Data(Vec::new()));
+        (None, None, _) => None,
+    };
+    let switching = opts.uid.is_some_and(|u| u != unsafe { libc::geteuid() }) || gid.is_some_and(|g| g != unsafe { libc::getegid() });
+    if switching && unsafe { libc::geteuid() } != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "spawning as another uid/gid needs the host to run as root"));
+    }
+    let groups = match (gid, &pw) {
+        (Some(g), Some(pw)) => group_list(&pw.name, g)?,
+        (Some(g), None)     => vec![g],
+        (None, _)           => Vec::new(),
+    };
+    Ok(Credentials { uid: opts.uid, gid, groups })
+}
+
+/// Switches the forked child to `creds`, between fork and exec; nothing here allocates
+/// or looks anything up. Supplementary groups and the gid have to go first: once the
+/// uid is changed the process no longer has the privilege to change them.
+#[cfg(unix)]
+unsafe fn drop_privileges(creds: &Credentials) -> io::Result<()> {
+    let Credentials { uid, gid, groups } = creds;
+    unsafe {
+        if let Some(g) = *gid {
+            if libc::geteuid() == 0 && libc::setgroups(groups.len() as _, groups.as_ptr()) == -1 { return Err(io::Error::last_os_error()); }
+            if libc::setgid(g) == -1 { return Err(io::Error::last_os_error()); }
         }
-        let mut out = Vec::new();
-        for m in msgs {
-            if let Msg::Data(d) = m { out.extend(d); }
+        if let Some(u) = *uid {
+            if libc::setuid(u) == -1 { return Err(io::Error::last_os_error()); }
+            if u != 0 && libc::setuid(0) != -1 { return Err(io::Error::from_raw_os_error(libc::EPERM)); }   // root must be gone for good
         }
-        Ok(Msg::Data(out))
     }
+    Ok(())
 }
 
-/* ---------- Pty wrapper ---------- */
+/// Reads `pid`'s usage from `/proc/<pid>/stat`: utime and stime in clock ticks, rss in pages.
+#[cfg(target_os = "linux")]
+fn proc_rusage(pid: u32) -> Option<PtyRusage> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // the command name may contain spaces or parens, so count fields from the last ')'
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();     // `n` as numbered in proc(5)
+    let tick = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+    Some(PtyRusage {
+        rss_bytes: field(24)? * page,
+        user_ms:   field(14)? * 1000 / tick,
+        system_ms: field(15)? * 1000 / tick,
+    })
+}
 
-struct Pty {
-    reader: Reader,
-    tx_w:   Sender<(Vec<u8>, usize)>,      // (buffer, len)
-    _slave: Box<dyn SlavePty + Send>,
-    master: Arc<Mutex<Box<dyn MasterPty + Send>>>,
-    killer: Arc<Mutex<Box<dyn ChildKiller + Send + Sync>>>,
-    exited: AtomicBool,
-    exit_code: AtomicI32,
-    pid:    c_int,
+#[cfg(target_os = "macos")]
+fn proc_rusage(pid: u32) -> Option<PtyRusage> {
+    let mut info: libc::proc_taskinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_taskinfo>() as c_int;
+    let n = unsafe {
+        libc::proc_pidinfo(pid as c_int, libc::PROC_PIDTASKINFO, 0, (&mut info as *mut libc::proc_taskinfo).cast(), size)
+    };
+    if n != size { return None; }
+    // CPU times are in Mach absolute-time units, which are only nanoseconds on Intel
+    let mut tb = libc::mach_timebase_info { numer: 1, denom: 1 };
+    #[allow(deprecated)]
+    unsafe { libc::mach_timebase_info(&mut tb); }
+    let ms = |t: u64| (t as u128 * tb.numer as u128 / tb.denom.max(1) as u128 / 1_000_000) as u64;
+    Some(PtyRusage {
+        rss_bytes: info.pti_resident_size,
+        user_ms:   ms(info.pti_total_user),
+        system_ms: ms(info.pti_total_system),
+    })
 }
 
-unsafe impl Send for Pty {}
-unsafe impl Sync for Pty {}
+/// Other platforms have no per-process usage lookup wired up yet.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn proc_rusage(_pid: u32) -> Option<PtyRusage> {
+    None
+}
 
-impl Pty {
-    fn new(cmd: Command, size: PtySize) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
-        let sys  = native_pty_system();
-        let pair = sys.openpty(size)?;
-        let mut child = pair.slave.spawn_command(cmd.to_builder())?;
-        let killer = Arc::new(Mutex::new(child.clone_killer()));
-        let pid    = child.process_id().map(|p| p as c_int).unwrap_or(ERROR);
+/// Blocks until `pid` has exited, without reaping it, or until `stop` is set; true if it exited.
+/// Lets the wait-thread of a `detached` child give up when its handle is closed.
+#[cfg(unix)]
+fn exited_or(pid: u32, stop: &AtomicBool) -> bool {
+    while !stop.load(Ordering::Relaxed) {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        match unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) } {
+            0 if unsafe { info.si_pid() } != 0 => return true,
+            0                                  => thread::sleep(Duration::from_millis(100)),
+            _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+            _                                  => return true,         // let `wait_child` sort it out
+        }
+    }
+    false
+}
 
-        /* channels */
-        let (tx_r, rx_r)   = unbounded::<Msg>();
-        let (tx_w, rx_w)   = unbounded::<(Vec<u8>, usize)>();
+/// Blocks until `fd` is readable, or until `stop` is set; true if readable.
+#[cfg(unix)]
+fn readable_or(fd: c_int, stop: &AtomicBool) -> bool {
+    let mut p = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    while !stop.load(Ordering::Relaxed) {
+        match unsafe { libc::poll(&mut p, 1, 100) } {
+            0                                                             => {}
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+            _                                                             => return true,
+        }
+    }
+    false
+}
 
-        let master = Arc::new(Mutex::new(pair.master));
+type SpawnedChild = (Box<dyn Child + Send + Sync>, Option<io::PipeReader>);
 
-        let pty = Arc::new(Self {
-            reader: Reader::new(rx_r),
-            tx_w,
-            _slave: pair.slave,
-            master: master.clone(),
-            killer,
-            exited: AtomicBool::new(false),
-            exit_code: AtomicI32::new(-1),
-            pid,
-        });
+/// argv and envp for the child's `execve`, built before the fork so the `pre_exec` hook
+/// only has to make the call. std's process builder keeps the environment in a map, as
+/// `CommandBuilder` does, so exec'ing through it would hand the child `cmd.env` sorted
+/// and without duplicates; this passes it on as given.
+#[cfg(unix)]
+struct Exec {
+    exe:   CString,
+    argv:  Vec<*const c_char>,                  // point into `_args` / `_vars`, NULL-terminated
+    envp:  Vec<*const c_char>,
+    _args: Vec<CString>,
+    _vars: Vec<CString>,
+}
 
-        /* wait-thread */
-        {
-            let tx = tx_r.clone();
-            let pty_clone = pty.clone();
-            thread::spawn(move || {
-                let status = child.wait();
-                if let Ok(exit_status) = status {
-                    let code = exit_status.exit_code() as i32;
-                    debug(&format!("exit_status.exit_code(): {}", code));
-                    pty_clone.exit_code.store(code, Ordering::Relaxed);
+// the pointers only lead into the strings `Exec` owns, which never change
+#[cfg(unix)]
+unsafe impl Send for Exec {}
+#[cfg(unix)]
+unsafe impl Sync for Exec {}
+
+#[cfg(unix)]
+impl Exec {
+    fn new(exe: &std::path::Path, cmd: &Command) -> io::Result<Self> {
+        let c_str = |s: &[u8]| CString::new(s)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL byte in the command, an argument or the environment"));
+        let args = std::iter::once(&cmd.cmd).chain(&cmd.args).map(|a| c_str(a.as_bytes())).collect::<io::Result<Vec<_>>>()?;
+        let vars = cmd.env.iter().map(|(k, v)| c_str(format!("{k}={v}").as_bytes())).collect::<io::Result<Vec<_>>>()?;
+        let ptrs = |v: &[CString]| v.iter().map(|s| s.as_ptr()).chain([std::ptr::null()]).collect();
+        Ok(Self { exe: c_str(exe.as_os_str().as_encoded_bytes())?, argv: ptrs(&args), envp: ptrs(&vars), _args: args, _vars: vars })
+    }
+
+    /// Replaces the forked child with the command; only returns if that failed.
+    unsafe fn run(&self) -> io::Error {
+        unsafe { libc::execve(self.exe.as_ptr(), self.argv.as_ptr(), self.envp.as_ptr()); }
+        io::Error::last_os_error()
+    }
+}
+
+/// Spawns `cmd` on the PTY the way portable-pty does: its own session with the slave
+/// as controlling terminal, signal dispositions reset, stray descriptors closed. On
+/// Unix every PTY spawn comes here, so the child gets `cmd.env` in order (see `Exec`).
+/// It also does what portable-pty's `CommandBuilder` can't express: `keep_fds` stay
+/// open in the child (portable-pty closes every descriptor above stderr before exec),
+/// and with `separate_stderr` stderr goes to a pipe whose read end is returned, `umask`
+/// is set before exec, and a `detached` child ignores SIGHUP so losing the terminal
+/// doesn't end it. Without `controlling_tty` the child still gets its own session but
+/// the slave isn't made its controlling terminal: Ctrl-C, Ctrl-Z and hangup no longer
+/// turn into signals, there is no foreground process group, and opening /dev/tty
+/// fails. Other non-CLOEXEC descriptors are still closed, so nothing else leaks through.
+#[cfg(unix)]
+fn spawn_direct(master: &dyn MasterPty, cmd: &Command, opts: &SpawnOptions) -> io::Result<SpawnedChild> {
+    use std::os::unix::{fs::OpenOptionsExt, process::CommandExt};
+
+    let fds = &opts.keep_fds;
+    if let Some(fd) = fds.iter().find(|&&fd| fd <= 2 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {fd} is not an open descriptor above stderr")));
+    }
+    if let Some(mask) = opts.umask.filter(|&m| m > 0o777) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("umask {mask:#o} is outside 0o777")));
+    }
+    let creds = credentials(opts)?;
+    let tty = master.as_raw_fd().and_then(tty_name)
+        .ok_or_else(|| io::Error::other("cannot locate the PTY slave"))?;
+    let tty = std::fs::OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(tty)?;
+
+    let exe = cmd.resolve_exe()?;
+    let exec = Exec::new(&exe, cmd)?;
+    let mut c = std::process::Command::new(&exe);
+    c.current_dir(&cmd.cwd)                          // argv and the environment go through `exec`
+        .stdin(tty.try_clone()?)
+        .stdout(tty.try_clone()?);
+    let err_pipe = if opts.separate_stderr {
+        let (r, w) = io::pipe()?;
+        c.stderr(w);
+        Some(r)
+    } else {
+        c.stderr(tty);
+        None
+    };
+
+    let keep = fds.to_vec();
+    let umask = opts.umask;
+    let detached = opts.detached;
+    let ctty = opts.controlling_tty;
+    unsafe {
+        c.pre_exec(move || {
+            if let Some(mask) = umask { libc::umask(mask as libc::mode_t); }
+            for sig in [libc::SIGCHLD, libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM, libc::SIGALRM] {
+                libc::signal(sig, libc::SIG_DFL);
+            }
+            if detached { libc::signal(libc::SIGHUP, libc::SIG_IGN); }
+            if libc::setsid() == -1 { return Err(io::Error::last_os_error()); }
+            if ctty && libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 { return Err(io::Error::last_os_error()); }
+
+            // CLOEXEC descriptors (std's exec-error pipe among them) go away on their own
+            let open: Vec<c_int> = std::fs::read_dir("/dev/fd")
+                .map(|d| d.filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok()).collect())
+                .unwrap_or_default();
+            for fd in open.into_iter().filter(|fd| *fd > 2 && !keep.contains(fd)) {
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags != -1 && flags & libc::FD_CLOEXEC == 0 { libc::close(fd); }
+            }
+            for &fd in &keep {
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == -1 {
+                    return Err(io::Error::last_os_error());
                 }
-                let _ = tx.send(Msg::End);
+            }
+            drop_privileges(&creds)?;                 // last, while the steps above still run privileged
+            Err(exec.run())
+        });
+    }
+    let child = c.spawn()?;
+    drop(c);                                          // closes our copy of the pipe's write end
+    Ok((Box::new(child), err_pipe))
+}
+
+/// Windows has no fd inheritance to speak of, and ConPTY gives the child a console
+/// rather than separate std handles, so neither option can be honoured there.
+#[cfg(windows)]
+fn spawn_direct(_master: &dyn MasterPty, _cmd: &Command, _opts: &SpawnOptions) -> io::Result<SpawnedChild> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "keep_fds and separate_stderr are not supported on Windows"))
+}
+
+/// Stands in for a PTY master when none could be allocated (`pipe_fallback`): the
+/// child's stdin, and its stdout and stderr merged into one pipe. There is no
+/// terminal, so sizes are only remembered and the child never hears about them.
+struct PipeMaster {
+    out:   io::PipeReader,
+    input: Mutex<Option<std::process::ChildStdin>>,
+    size:  Mutex<PtySize>,
+}
+
+impl MasterPty for PipeMaster {
+    fn resize(&self, size: PtySize) -> Result<(), anyhow::Error> {
+        *self.size.locked() = size;
+        Ok(())
+    }
+    fn get_size(&self) -> Result<PtySize, anyhow::Error> {
+        Ok(*self.size.locked())
+    }
+    fn try_clone_reader(&self) -> Result<Box<dyn Read + Send>, anyhow::Error> {
+        Ok(Box::new(self.out.try_clone()?))
+    }
+    fn take_writer(&self) -> Result<Box<dyn Write + Send>, anyhow::Error> {
+        let input = self.input.locked().take().ok_or_else(|| anyhow::anyhow!("writer already taken"))?;
+        Ok(Box::new(input))
+    }
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t> { None }
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::unix::io::RawFd> { None }
+}
+
+/// Spawns `cmd` on plain pipes, for when `openpty` fails. With `separate_stderr`
+/// stderr gets its own pipe as it would on a PTY. On Unix the child still gets its
+/// own process group, so group signals work, and `uid`/`gid` apply; `keep_fds` and
+/// `umask` don't.
+fn spawn_piped(cmd: &Command, size: PtySize, opts: &SpawnOptions) -> io::Result<(PipeMaster, SpawnedChild)> {
+    #[cfg(unix)]
+    let exe = cmd.resolve_exe()?;
+    #[cfg(not(unix))]
+    let exe = std::path::PathBuf::from(&cmd.cmd);     // resolved by CreateProcess itself
+    let mut c = std::process::Command::new(&exe);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        c.process_group(0);
+        let creds = credentials(opts)?;
+        let exec = Exec::new(&exe, cmd)?;           // the environment in order, as `spawn_direct` passes it
+        unsafe { c.pre_exec(move || { drop_privileges(&creds)?; Err(exec.run()) }); }
+    }
+    #[cfg(not(unix))]
+    c.args(&cmd.args).env_clear().envs(cmd.env.iter().map(|(k, v)| (k, v)));
+    let (out, out_w) = io::pipe()?;
+    c.current_dir(&cmd.cwd)
+        .stdin(std::process::Stdio::piped())
+        .stdout(out_w.try_clone()?);
+    let err_pipe = if opts.separate_stderr {
+        let (r, w) = io::pipe()?;
+        c.stderr(w);
+        Some(r)
+    } else {
+        c.stderr(out_w);
+        None
+    };
+    let mut child = c.spawn()?;
+    drop(c);                                          // our copies of the write ends, so EOF can arrive
+    let master = PipeMaster { out, input: Mutex::new(child.stdin.take()), size: Mutex::new(size) };
+    Ok((master, (Box::new(child), err_pipe)))
+}
+
+/* ---------- command struct ---------- */
+
+/// The spawn's `cwd` is missing or not a directory.
+#[derive(Debug)]
+struct InvalidCwd(String);
+
+impl std::fmt::Display for InvalidCwd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "working directory {:?} does not exist or is not a directory", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCwd {}
+
+/// The registry already holds `bun_pty_set_max_handles` PTYs.
+#[derive(Debug)]
+struct TooManyHandles(usize);
+
+impl std::fmt::Display for TooManyHandles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "handle limit of {} reached", self.0)
+    }
+}
+
+impl std::error::Error for TooManyHandles {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Command {
+    cmd: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,                 // in order, duplicates kept; on Unix the child gets it as is
+    cwd: String,
+}
+
+impl Command {
+    fn from_argv(argv: &[String], cwd: &str) -> Self {
+        let (cmd, args) = match argv.split_first() {
+            Some((cmd, args)) => (cmd.clone(), args.to_vec()),
+            None              => (String::new(), Vec::new()),
+        };
+        let env = std::env::vars().collect();              // forward everything
+
+        Self { cmd, args, env, cwd: cwd.to_owned() }
+    }
+
+    fn from_cmdline(cmdline: &str, cwd: &str) -> Result<Self, shell_words::ParseError> {
+        let tokens = split(cmdline)?;                      // shell-accurate split
+        if tokens.is_empty() {
+            return Ok(Self {
+                cmd: String::new(),
+                args: Vec::new(),
+                env: Vec::new(),
+                cwd: cwd.to_owned(),
             });
         }
 
-        /* read-thread */
-        {
-            let mut rdr = master.lock().unwrap().try_clone_reader()?;
-            let tx = tx_r.clone();
-            thread::spawn(move || {
-                let mut buf = vec![0; 8192];
-                loop {
-                    match rdr.read(&mut buf) {
-                        Ok(0) => break,
-                        Ok(n) => { let _ = tx.send(Msg::Data(buf[..n].to_vec())); }
-                        Err(_) => break,
-                    }
-                }
-                let _ = tx.send(Msg::End);
-            });
+        let cmd  = tokens[0].clone();
+        let args = tokens[1..].to_vec();
+
+        let env = std::env::vars().collect();              // forward everything
+
+        Ok(Self { cmd, args, env, cwd: cwd.to_owned() })
+    }
+
+    /// `line` run by a shell, for `use_shell`: `shell -c line` on Unix (`/bin/sh` by
+    /// default), `shell /c line` on Windows (`%COMSPEC%`, else `cmd.exe`). On Unix `args`
+    /// become `$1`, `$2`…; on Windows they are appended to the line.
+    ///
+    /// The shell expands everything in `line`: quotes, `$VAR`, `$(…)`, globs, `;`, `&&`,
+    /// `|`. Never build it from untrusted input; pass such values in `args` (Unix) and
+    /// quote their use, e.g. `"$1"`, or spawn without `use_shell`.
+    fn through_shell(line: &str, args: &[String], shell: Option<&str>, cwd: &str) -> Self {
+        #[cfg(unix)]
+        let argv = {
+            let sh = shell.filter(|s| !s.is_empty()).unwrap_or("/bin/sh");
+            let mut argv = vec![sh.to_owned(), "-c".into(), line.to_owned()];
+            if !args.is_empty() {
+                argv.push(sh.to_owned());                      // `$0`
+                argv.extend_from_slice(args);
+            }
+            argv
+        };
+        #[cfg(windows)]
+        let argv = {
+            let sh = shell.filter(|s| !s.is_empty()).map(String::from)
+                .or_else(|| std::env::var("COMSPEC").ok().filter(|s| !s.is_empty()))
+                .unwrap_or_else(|| "cmd.exe".into());
+            [vec![sh, "/c".into(), line.to_owned()], args.to_vec()].concat()
+        };
+        Self::from_argv(&argv, cwd)
+    }
+
+    /// What a command-line spawn runs: `cmdline` split into argv and exec'd, or with
+    /// `use_shell`, handed whole to the shell.
+    fn from_cmdline_with(cmdline: &str, cwd: &str, opts: &SpawnOptions) -> Result<Self, shell_words::ParseError> {
+        if opts.use_shell { return Ok(Self::through_shell(cmdline, &[], opts.shell.as_deref(), cwd)); }
+        Self::from_cmdline(cmdline, cwd)
+    }
+
+    /// The user's default shell: `$SHELL`, else the passwd entry, else `/bin/sh`, with
+    /// `-l` when `login` is set. On Windows `%COMSPEC%` (or `cmd.exe`); `login` has no
+    /// meaning there and is ignored.
+    fn default_shell(cwd: &str, login: bool) -> Self {
+        #[cfg(unix)]
+        let argv = {
+            let shell = std::env::var("SHELL").ok().filter(|s| !s.is_empty())
+                .or_else(passwd_shell)
+                .unwrap_or_else(|| "/bin/sh".into());
+            let mut argv = vec![shell];
+            if login { argv.push("-l".into()); }
+            argv
+        };
+        #[cfg(windows)]
+        let argv = {
+            let _ = login;
+            vec![std::env::var("COMSPEC").ok().filter(|s| !s.is_empty()).unwrap_or_else(|| "cmd.exe".into())]
+        };
+        Self::from_argv(&argv, cwd)
+    }
+
+    /// Replaces the inherited environment with exactly `env`.
+    fn with_env(mut self, env: Vec<(String, String)>) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Sets `key`, dropping any earlier entries for it.
+    fn set_var(&mut self, key: &str, val: &str) {
+        self.env.retain(|(k, _)| k != key);
+        self.env.push((key.into(), val.into()));
+    }
+
+    /// The value `key` ends up with: its last entry.
+    fn var(&self, key: &str) -> Option<&str> {
+        self.env.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// Resolves `cmd` the way portable-pty will (cwd first, then `PATH`) so that
+    /// a missing or non-executable binary surfaces as a proper `io::Error`.
+    #[cfg(unix)]
+    fn resolve_exe(&self) -> io::Result<std::path::PathBuf> {
+        use std::path::Path;
+
+        fn executable(p: &Path) -> bool {
+            let Ok(c) = CString::new(p.as_os_str().as_encoded_bytes()) else { return false; };
+            p.is_file() && unsafe { libc::access(c.as_ptr(), libc::X_OK) } == 0
+        }
+        if self.cmd.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command"));
         }
 
-        /* write-thread  (length-aware) */
-        {
-            let mut wtr = master.lock().unwrap().take_writer()?;
-            thread::spawn(move || {
-                while let Ok((data, len)) = rx_w.recv() {
-                    if wtr.write_all(&data[..len]).is_err() { break; }
-                    let _ = wtr.flush();
-                }
-            });
+        let exe = Path::new(&self.cmd);
+        let mut candidates = Vec::new();
+        if exe.is_absolute() {
+            candidates.push(exe.to_path_buf());
+        } else {
+            candidates.push(Path::new(&self.cwd).join(exe));
+            let path = self.var("PATH").map(String::from).or_else(|| std::env::var("PATH").ok()).unwrap_or_default();
+            candidates.extend(std::env::split_paths(&path).map(|d| d.join(exe)));
         }
 
-        Ok(pty)
+        let mut denied = false;
+        for c in candidates {
+            if executable(&c) { return Ok(c); }
+            denied |= c.is_file();
+        }
+        Err(if denied {
+            io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is not executable", self.cmd))
+        } else {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} not found in cwd or PATH", self.cmd))
+        })
     }
 
-    fn read(&self) -> Result<Msg, Box<dyn std::error::Error + Send + Sync>> {
-        let m = self.reader.read()?;
-        if matches!(m, Msg::End) { self.exited.store(true, Ordering::Relaxed); }
-        Ok(m)
+    /// Checks that `cwd` is a directory, which portable-pty would otherwise quietly swap
+    /// for `$HOME`. With `cwd_fallback`, the host's cwd is used instead of failing.
+    fn checked_cwd(mut self, opts: &SpawnOptions) -> Result<Self, InvalidCwd> {
+        if std::path::Path::new(&self.cwd).is_dir() { return Ok(self); }
+        let host = std::env::current_dir().ok().filter(|_| opts.cwd_fallback);
+        match host {
+            Some(dir) => {
+                debug_as(&opts.label, &format!("cwd {:?} is not a directory, using {}", self.cwd, dir.display()));
+                self.cwd = dir.to_string_lossy().into_owned();
+                Ok(self)
+            }
+            None => Err(InvalidCwd(self.cwd)),
+        }
     }
 
-    fn write(&self, data: *const u8, len: usize) -> c_int {
-        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
-        let slice = unsafe { std::slice::from_raw_parts(data, len) };
-        match self.tx_w.send((slice.to_vec(), len)) {
-            Ok(_)  => SUCCESS,
-            Err(_) => ERROR,
-        }
+    /// Keeps only the listed variables of the inherited environment; no keys keeps all.
+    fn inherit_only(mut self, keys: &[String]) -> Self {
+        if !keys.is_empty() { self.env.retain(|(k, _)| keys.contains(k)); }
+        self
     }
 
-    fn resize(&self, size: PtySize) -> c_int {
-        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
-        self.master.lock().unwrap().resize(size).map(|_| SUCCESS).unwrap_or(ERROR)
+    /// The environment a spawn's options ask for: `inherit_env_keys`, then `env_blocklist`.
+    fn options_env(self, opts: &SpawnOptions) -> Self {
+        self.inherit_only(&opts.inherit_env_keys).sanitized(&opts.env_blocklist)
     }
-    fn kill(&self) -> c_int {
-        let res = self.killer.lock().map(|mut k| k.kill());
-        match res {
-            Ok(Ok(_)) => { self.exited.store(true, Ordering::Relaxed); SUCCESS }
-            _         => ERROR,
+
+    /// Drops env vars named in `blocklist`; an entry ending in `*` matches by prefix (`AWS_*`).
+    fn sanitized(mut self, blocklist: &[String]) -> Self {
+        let blocked = |k: &str| blocklist.iter().any(|b| match b.strip_suffix('*') {
+            Some(prefix) => k.starts_with(prefix),
+            None         => k == b,
+        });
+        self.env.retain(|(k, _)| !blocked(k));
+        self
+    }
+
+    /// Sets TERM / COLORTERM / LANG from `opts`, overriding any inherited values.
+    fn with_term_env(mut self, opts: &SpawnOptions) -> Self {
+        let vars = [("TERM", Some(&opts.term)), ("COLORTERM", opts.colorterm.as_ref()), ("LANG", opts.lang.as_ref())];
+        for (k, v) in vars {
+            if let Some(v) = v.filter(|v| !v.is_empty()) { self.set_var(k, v); }
+        }
+        self
+    }
+
+    /// On Windows portable-pty joins argv into one command line with the usual
+    /// CreateProcess/CRT quoting (spaces and embedded quotes quoted, backslashes doubled
+    /// only before a quote), so arguments reach a normal child as given. A child that
+    /// is cmd.exe or a batch file re-parses the line with its own rules (`^`, `&`, `%`),
+    /// which no argv quoting can cover. Unix spawns don't use the builder (`spawn_direct`).
+    #[cfg(windows)]
+    fn to_builder(&self) -> CommandBuilder {
+        let mut b = CommandBuilder::new(&self.cmd);
+        b.cwd(&self.cwd);
+        b.env_clear();                                      // `env` is the whole environment
+        for a in &self.args {
+            b.arg(a);
+        }
+        for (k, v) in &self.env {                           // Windows wants the block sorted anyway
+            b.env(k, v);
         }
+        b
     }
 }
 
-/* ---------- registry ---------- */
+/* ---------- spawn options ---------- */
 
-use std::sync::atomic::AtomicU32;
-lazy_static::lazy_static! {
-    static ref REG: Mutex<HashMap<u32, Arc<Pty>>> = Mutex::new(HashMap::new());
+/// Extra spawn settings, passed over FFI as a JSON object; omitted fields take their defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct SpawnOptions {
+    env_blocklist: Vec<String>,
+    inherit_env_keys: Vec<String>,              // pass only these host variables through; empty = all
+    read_buffer_size: usize,                    // clamped to READ_BUFFER_RANGE
+    coalesce_writes: bool,                      // batch queued writes into one write+flush
+    raw_mode: bool,                             // start with ICANON/ECHO off (Unix)
+    initial_input: String,                      // written before anything the caller sends
+    close_stdin: bool,                          // EOF right after `initial_input`; no writes after
+    term: String,                               // TERM for the child; empty leaves it alone
+    colorterm: Option<String>,
+    lang: Option<String>,
+    read_channel_capacity: usize,               // chunks buffered before the read-thread blocks; 0 = unbounded
+    keep_fds: Vec<c_int>,                       // extra descriptors inherited by the child (Unix)
+    separate_stderr: bool,                      // stderr through a pipe, read with `bun_pty_read_stderr` (Unix)
+    report_eof: bool,                           // `bun_pty_read` returns EOF_REACHED between output EOF and reap
+    manage_reads: bool,                         // false: no read-thread, the caller reads via `bun_pty_raw_read`
+    timestamps: bool,                           // stamp each chunk for `bun_pty_read_timed`
+    exit_drain_ms: u64,                         // how long after the reap `End` waits on output that won't close
+    cwd_fallback: bool,                         // spawn in the host's cwd if `cwd` isn't a directory
+    suppress_ms: u32,                           // discard terminal output for this long after spawn (banners, MOTD)
+    utf8_chunks: bool,                          // reads never split a multibyte UTF-8 character
+    umask: Option<u32>,                         // file-creation mask for the child, at most 0o777 (Unix; ignored on Windows)
+    pipe_fallback: bool,                        // spawn on plain pipes if no PTY can be allocated
+    detached: bool,                             // child ignores SIGHUP and outlives its handle (Unix)
+    scrollback_bytes: usize,                    // recent output kept for `bun_pty_get_scrollback`; 0 = none
+    controlling_tty: bool,                      // false: the slave isn't made the child's controlling terminal (Unix)
+    uid: Option<u32>,                           // run the child as this user; host must be root (Unix)
+    gid: Option<u32>,                           // …and group; defaults to `uid`'s primary group
+    label: String,                              // names the PTY in debug output and `bun_pty_get_label`
+    use_shell: bool,                            // run the command line through `shell` instead of exec'ing it
+    shell: Option<String>,                      // for `use_shell`; `/bin/sh` (Unix) or `%COMSPEC%` by default
 }
-static NEXT: AtomicU32 = AtomicU32::new(1);
 
-fn store(pty: Arc<Pty>) -> u32 {
-    let id = NEXT.fetch_add(1, Ordering::Relaxed);
-    REG.lock().unwrap().insert(id, pty);
-    id
+impl Default for SpawnOptions {
+    fn default() -> Self {
+        Self {
+            env_blocklist: Vec::new(),
+            inherit_env_keys: Vec::new(),
+            read_buffer_size: 8192,
+            coalesce_writes: false,
+            raw_mode: false,
+            initial_input: String::new(),
+            close_stdin: false,
+            term: "xterm-256color".into(),
+            colorterm: None,
+            lang: None,
+            read_channel_capacity: 1024,
+            keep_fds: Vec::new(),
+            separate_stderr: false,
+            report_eof: false,
+            manage_reads: true,
+            timestamps: false,
+            exit_drain_ms: 20,
+            cwd_fallback: false,
+            suppress_ms: 0,
+            utf8_chunks: false,
+            umask: None,
+            pipe_fallback: false,
+            detached: false,
+            scrollback_bytes: 0,
+            controlling_tty: true,
+            uid: None,
+            gid: None,
+            label: String::new(),
+            use_shell: false,
+            shell: None,
+        }
+    }
 }
-fn with<F: FnOnce(&Arc<Pty>) -> c_int>(id: u32, f: F) -> c_int {
-    REG.lock().unwrap().get(&id).map(f).unwrap_or(ERROR)
+
+impl SpawnOptions {
+    /// Whether the spawn needs something portable-pty's `CommandBuilder` can't do; on
+    /// Windows, where only it can spawn on ConPTY, those options fail the spawn.
+    #[cfg(windows)]
+    fn needs_direct(&self) -> bool {
+        !self.keep_fds.is_empty() || self.separate_stderr
+    }
 }
 
-/* ---------- FFI ---------- */
+const READ_BUFFER_RANGE: (usize, usize) = (1024, 1024 * 1024);
+const WRITE_CHUNK: usize = 4096;                // largest single write before priority writes get a turn
+const RATE_BURST: Duration = Duration::from_millis(100);   // how much unused read rate limit can build up
+const FG_POLL: Duration = Duration::from_millis(250);      // how often `set_on_fg_change` checks `tcgetpgrp`
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn bun_pty_spawn(
-    cmd:  *const c_char,
-    cwd:  *const c_char,
-    cols: c_int,
-    rows: c_int,
-) -> c_int {
-    if cmd.is_null() || cwd.is_null() || cols <= 0 || rows <= 0 { return ERROR; }
+/// Everything needed for a spawn in one JSON object, options flattened alongside:
+/// `{"cmd": "ls", "args": ["-l"], "cwd": "/tmp", "cols": 80, "rows": 24, "term": "vt100"}`.
+/// `env` omitted inherits the host environment; given, it replaces it. With `use_shell`,
+/// `cmd` is a command line for the shell and `args` are passed to it after the line.
+#[derive(Debug, Deserialize)]
+struct SpawnSpec {
+    cmd:  String,
+    #[serde(default)]
+    args: Vec<String>,
+    env:  Option<EnvPairs>,
+    cwd:  Option<String>,
+    #[serde(default = "SpawnSpec::default_cols")]
+    cols: u16,
+    #[serde(default = "SpawnSpec::default_rows")]
+    rows: u16,
+    #[serde(flatten)]
+    options: SpawnOptions,
+}
 
-    let cmdline = unsafe { CStr::from_ptr(cmd) }.to_string_lossy();
-    let cwd     = unsafe { CStr::from_ptr(cwd) }.to_string_lossy();
+impl SpawnSpec {
+    fn default_cols() -> u16 { 80 }
+    fn default_rows() -> u16 { 24 }
 
-    let size = PtySize { cols: cols as u16, rows: rows as u16, pixel_width: 0, pixel_height: 0 };
-    match Pty::new(Command::from_cmdline(&cmdline, &cwd), size) {
-        Ok(p)  => store(p) as c_int,
-        Err(e) => { debug(&format!("spawn error: {e}")); ERROR },
+    fn command(&self) -> Command {
+        let cwd = self.cwd.clone().unwrap_or_else(|| {
+            std::env::current_dir().map(|d| d.to_string_lossy().into_owned()).unwrap_or_default()
+        });
+        let cmd = if self.options.use_shell {
+            Command::through_shell(&self.cmd, &self.args, self.options.shell.as_deref(), &cwd)
+        } else {
+            let argv: Vec<String> = std::iter::once(self.cmd.clone()).chain(self.args.iter().cloned()).collect();
+            Command::from_argv(&argv, &cwd)
+        };
+        let keys = &self.options.inherit_env_keys;
+        let mut cmd = match &self.env {
+            Some(env) if keys.is_empty() => cmd.with_env(env.0.clone()),
+            _                            => cmd.inherit_only(keys),
+        };
+        if !keys.is_empty() && let Some(env) = &self.env {
+            for (k, v) in &env.0 { cmd.set_var(k, v); }   // explicit values on top of the inherited subset
+        }
+        cmd.sanitized(&self.options.env_blocklist)
     }
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn bun_pty_write(
-    handle: c_int,
-    data:   *const u8,
-    len:    c_int,
-) -> c_int {
-    if handle <= 0 || data.is_null() || len < 0 { return ERROR; }
-    with(handle as u32, |p| p.write(data, len as usize))
+/// `SpawnSpec::env`: a JSON object of strings, read as pairs in the order written, a
+/// key given twice included twice. On Unix the child's environment is exactly that
+/// list; which of two duplicates `getenv` finds is up to the child's libc (glibc's
+/// takes the first). Windows sorts the block and keeps the last of a duplicate.
+#[derive(Debug)]
+struct EnvPairs(Vec<(String, String)>);
+
+impl<'de> Deserialize<'de> for EnvPairs {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct Pairs;
+        impl<'de> serde::de::Visitor<'de> for Pairs {
+            type Value = EnvPairs;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an object of string values")
+            }
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<EnvPairs, A::Error> {
+                let mut pairs = Vec::new();
+                while let Some(kv) = map.next_entry()? { pairs.push(kv); }
+                Ok(EnvPairs(pairs))
+            }
+        }
+        d.deserialize_map(Pairs)
+    }
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn bun_pty_read(
-    handle: c_int,
+/// Parses the JSON options string; a null pointer means all defaults.
+unsafe fn parse_options(opts: *const c_char) -> Result<SpawnOptions, serde_json::Error> {
+    if opts.is_null() { return Ok(SpawnOptions::default()); }
+    serde_json::from_str(&unsafe { CStr::from_ptr(opts) }.to_string_lossy())
+}
+
+/* ---------- async message channel ---------- */
+
+#[derive(Debug, PartialEq, Eq)]
+enum Msg {
+    Data(Vec<u8>, u64),                         // bytes, nanos since spawn (0 unless `timestamps`)
+    End(i32),                                   // exit code; sent once, after it's stored
+}
+
+struct Reader {
+    rx:       Receiver<Msg>,
+    done:     AtomicBool,
+    leftover: Mutex<(Vec<u8>, u64)>,            // drained but not yet handed out, with its timestamp
+    pending:  AtomicUsize,                      // bytes queued or left over, not yet returned
+    exit:     AtomicI32,                        // code carried by `End`, valid once `done`
+    line:     Mutex<Vec<u8>>,                   // `read_line` bytes with no newline yet
+    utf8:     bool,                             // cut chunks on UTF-8 boundaries (`utf8_chunks`)
+    plain:    Mutex<ansi::Stripper>,            // `read_plain` state, carried across chunks
+}
+impl Reader {
+    fn new(rx: Receiver<Msg>, utf8: bool) -> Self {
+        Self {
+            rx,
+            utf8,
+            done:     AtomicBool::new(false),
+            leftover: Mutex::new((Vec::new(), 0)),
+            pending:  AtomicUsize::new(0),
+            exit:     AtomicI32::new(-1),
+            line:     Mutex::new(Vec::new()),
+            plain:    Mutex::new(ansi::Stripper::default()),
+        }
+    }
+
+    /// Moves queued data into `out` until it holds `max` bytes; true once `End` is seen.
+    /// Stopping at `max` leaves the rest in the channel so backpressure still applies.
+    /// `marks` records where each chunk starts in `out` and when it was read.
+    fn drain(&self, out: &mut Vec<u8>, marks: &mut Vec<(usize, u64)>, max: usize) -> bool {
+        while out.len() < max {
+            match self.rx.try_recv() {
+                Ok(Msg::Data(d, ts)) => { marks.push((out.len(), ts)); out.extend(d); }
+                Ok(Msg::End(code))   => { self.exit.store(code, Ordering::Relaxed); return true; }
+                Err(_)               => break,
+            }
+        }
+        false
+    }
+
+    /// Picks up what the last call left over, as the first chunk of this one.
+    fn resume(left: &mut (Vec<u8>, u64)) -> (Vec<u8>, Vec<(usize, u64)>) {
+        let (out, ts) = std::mem::take(left);
+        let marks = if out.is_empty() { Vec::new() } else { vec![(0, ts)] };
+        (out, marks)
+    }
+
+    /// Returns the first `max` bytes of `out`, stamped with its oldest chunk, and keeps
+    /// the rest, stamped with the chunk it starts in, for the next call; `None` if
+    /// that leaves nothing to return.
+    fn hand_out(&self, left: &mut (Vec<u8>, u64), mut out: Vec<u8>, marks: &[(usize, u64)], max: usize) -> Option<Msg> {
+        if out.len() > max {
+            let ts = marks.iter().rev().find(|m| m.0 <= max).map_or(0, |m| m.1);
+            *left = (out.split_off(max), ts);
+        }
+        self.pending.fetch_sub(out.len(), Ordering::Relaxed);
+        (!out.is_empty()).then(|| Msg::Data(out, marks.first().map_or(0, |m| m.1)))
+    }
+
+    fn end(&self) -> Msg {
+        Msg::End(self.exit.load(Ordering::Relaxed))
+    }
+
+    /// Returns at most `max` bytes; anything beyond that is kept for the next call.
+    /// `End` is always queued after the last data, so it's reported as soon as it
+    /// has been seen and nothing is left over. `None` means nothing can be handed out
+    /// yet; data is never empty, so it can't be mistaken for either.
+    ///
+    /// With `utf8` set a multibyte character is never split across calls: the cut
+    /// backs up to a character boundary and an incomplete trailing sequence waits for
+    /// the rest of its bytes. Once `End` is seen whatever is left goes out as is.
+    fn read(&self, max: usize) -> Option<Msg> {
+        let mut left = self.leftover.locked();
+        let (mut out, mut marks) = Self::resume(&mut left);
+        if !self.done.load(Ordering::Relaxed) && self.drain(&mut out, &mut marks, max) {
+            self.done.store(true, Ordering::Relaxed);
+        }
+        let done = self.done.load(Ordering::Relaxed);
+        if out.is_empty() && done { return Some(self.end()); }
+        let cut = match max.min(out.len()) {
+            n if !self.utf8    => n,
+            n if n < out.len() => Some(utf8_floor(&out, n)).filter(|&i| i > 0).unwrap_or(n),
+            n if done          => n,
+            _                  => utf8_tail(&out),
+        };
+        self.hand_out(&mut left, out, &marks, cut)
+    }
+
+    /// `read` with escape sequences filtered out. A chunk that was nothing but escapes
+    /// is skipped rather than returned empty, as long as more is queued.
+    fn read_plain(&self, max: usize) -> Option<Msg> {
+        let mut strip = self.plain.locked();
+        loop {
+            match self.read(max) {
+                Some(Msg::Data(d, ts)) => {
+                    let out = strip.strip(&d);
+                    if !out.is_empty() { return Some(Msg::Data(out, ts)); }
+                }
+                m => return m,
+            }
+        }
+    }
+
+    /// Like `read`, but with nothing to hand out blocks for the next chunk, up to
+    /// `timeout` (`None` = no limit); `None` once that has passed.
+    fn read_timeout(&self, max: usize, timeout: Option<Duration>) -> Option<Msg> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(m) = self.read(max) { return Some(m); }
+            let got = match deadline {
+                Some(d) => self.rx.recv_timeout(d.saturating_duration_since(Instant::now())).map_err(|e| e.is_timeout()),
+                None    => self.rx.recv().map_err(|_| false),
+            };
+            match got {
+                Ok(Msg::Data(d, ts)) => {
+                    let mut left = self.leftover.locked();
+                    if left.0.is_empty() { left.1 = ts; }
+                    left.0.extend(d);                   // `read` picks it up from here
+                }
+                Ok(Msg::End(code)) => {
+                    self.exit.store(code, Ordering::Relaxed);
+                    self.done.store(true, Ordering::Relaxed);
+                }
+                Err(true)  => return None,
+                Err(false) => return self.read(max),    // sender gone without `End`
+            }
+        }
+    }
+
+    /// Bytes buffered and not yet read, for `bun_pty_bytes_available`.
+    fn available(&self) -> c_int {
+        let n = self.pending.load(Ordering::Relaxed);
+        if n == 0 && self.done.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        n.min(c_int::MAX as usize) as c_int
+    }
+
+    /// The first `max` bytes `read` would return, left in place for it. Pulls queued
+    /// chunks into the leftover as needed, so they come back as one chunk stamped
+    /// with its oldest part.
+    fn peek(&self, max: usize) -> Vec<u8> {
+        let mut left = self.leftover.locked();
+        if left.0.len() < max && !self.done.load(Ordering::Relaxed) {
+            let (mut out, mut marks) = Self::resume(&mut left);
+            if self.drain(&mut out, &mut marks, max) { self.done.store(true, Ordering::Relaxed); }
+            *left = (out, marks.first().map_or(0, |m| m.1));
+        }
+        left.0[..left.0.len().min(max)].to_vec()
+    }
+
+    /// Returns the next complete line with `\r\n` folded to `\n`, or `None` if
+    /// none has arrived yet. A line longer than `max` comes back in pieces cut on
+    /// UTF-8 boundaries, or at `max` if there is none to back up to (binary output);
+    /// an unterminated last line is flushed once `End` is seen.
+    fn read_line(&self, max: usize) -> Option<Msg> {
+        let mut line = self.line.locked();
+        let mut ended = false;
+        if !line.contains(&b'\n') {
+            match self.read(usize::MAX) {
+                Some(Msg::Data(d, _))                => line.extend(d),
+                Some(Msg::End(_)) if line.is_empty() => return Some(self.end()),
+                Some(Msg::End(_))                    => ended = true,
+                None                                 => {}
+            }
+        }
+        // the `\r` of a `\r\n` is dropped below, so it doesn't count against `max`
+        let fits = |i: usize| i - (line[..i].ends_with(b"\r") as usize) < max;
+        let take = match line.iter().position(|&b| b == b'\n') {
+            Some(i) if fits(i)    => i + 1,
+            _ if line.len() > max => Some(utf8_floor(&line, max)).filter(|&i| i > 0).unwrap_or(max),
+            None if ended         => line.len(),
+            _                     => return None,
+        };
+        let rest = line.split_off(take);
+        let mut out = std::mem::replace(&mut *line, rest);
+        if out.ends_with(b"\r\n") { out.remove(out.len() - 2); }
+        Some(Msg::Data(out, 0))
+    }
+}
+
+/// Largest index `<= max` that does not fall inside a UTF-8 sequence.
+fn utf8_floor(b: &[u8], max: usize) -> usize {
+    let mut i = max.min(b.len());
+    while i > 0 && i < b.len() && b[i] & 0xC0 == 0x80 { i -= 1; }
+    i
+}
+
+/// Where an unfinished multibyte sequence at the end of `b` starts, or `b.len()` if
+/// the last character is complete.
+fn utf8_tail(b: &[u8]) -> usize {
+    let Some(i) = (b.len().saturating_sub(4)..b.len()).rev().find(|&i| b[i] & 0xC0 != 0x80) else {
+        return b.len();
+    };
+    let width = match b[i] {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _           => 1,
+    };
+    if i + width > b.len() { i } else { b.len() }
+}
+
+/// Raw bytes whose `encoding` fits in `len` bytes, or None for an unknown encoding.
+fn encodable(encoding: c_int, len: usize) -> Option<usize> {
+    match encoding {
+        ENCODING_RAW    => Some(len),
+        ENCODING_BASE64 => Some(len / 4 * 3),
+        ENCODING_HEX    => Some(len / 2),
+        _               => None,
+    }
+}
+
+fn encode(encoding: c_int, data: &[u8]) -> Vec<u8> {
+    const B64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    match encoding {
+        ENCODING_BASE64 => data.chunks(3).flat_map(|c| {
+            let n = (c[0] as u32) << 16 | (*c.get(1).unwrap_or(&0) as u32) << 8 | *c.get(2).unwrap_or(&0) as u32;
+            let sextet = |i: u32| B64[(n >> (18 - 6 * i) & 63) as usize];
+            [sextet(0), sextet(1), if c.len() > 1 { sextet(2) } else { b'=' }, if c.len() > 2 { sextet(3) } else { b'=' }]
+        }).collect(),
+        ENCODING_HEX => data.iter().flat_map(|b| [HEX[(b >> 4) as usize], HEX[(b & 15) as usize]]).collect(),
+        _            => data.to_vec(),
+    }
+}
+
+/* ---------- Pty wrapper ---------- */
+
+type WriteMsg = (Vec<u8>, usize, Option<Sender<c_int>>);   // (buffer, len, where to report the bytes written)
+
+type DataCallback = extern "C" fn(handle: c_int, data: *const u8, len: usize, user: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct OnData {
+    cb:     DataCallback,
+    handle: c_int,
+    user:   *mut c_void,
+}
+
+/// A `bun_pty_subscribe` sub-handle: its own copy of the output stream.
+struct Subscriber {
+    id:     u32,
+    tx:     Sender<Msg>,                        // unbounded, so a slow subscriber never stalls the PTY
+    reader: Reader,
+}
+
+type ExitCallback = extern "C" fn(handle: c_int, exit_code: i32, signal: i32, user: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct OnExit {
+    cb:     ExitCallback,
+    handle: c_int,
+    user:   *mut c_void,
+}
+
+type HighWaterCallback = extern "C" fn(handle: c_int, queued: c_int, user: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct OnHighWater {
+    cb:     HighWaterCallback,
+    handle: c_int,
+    user:   *mut c_void,
+    mark:   usize,                              // queued writes that trigger `cb`
+}
+
+type ResizeCallback = extern "C" fn(handle: c_int, cols: c_int, rows: c_int, user: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct OnResize {
+    cb:     ResizeCallback,
+    handle: c_int,
+    user:   *mut c_void,
+}
+
+type FgChangeCallback = extern "C" fn(handle: c_int, pgid: c_int, user: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct OnFgChange {
+    cb:     FgChangeCallback,
+    handle: c_int,
+    user:   *mut c_void,
+}
+
+/// State of the watcher thread behind `set_on_fg_change`.
+#[cfg(unix)]
+#[derive(Default)]
+struct FgWatch {
+    cb:      Option<OnFgChange>,                // None = off
+    last:    c_int,                             // foreground group as last seen
+    running: bool,
+}
+
+/// State shared with the timer thread behind `resize_debounced`.
+#[derive(Default)]
+struct Debounce {
+    pending: Option<(PtySize, Instant)>,       // latest size and when to apply it
+    running: bool,                             // timer thread started
+}
+
+/// Lifetime I/O counters, updated lock-free by the threads that do the I/O.
+#[derive(Default)]
+struct Counters {
+    bytes_read:    AtomicU64,
+    bytes_written: AtomicU64,
+    chunks_read:   AtomicU64,
+}
+
+/// Snapshot of `Counters` for `bun_pty_get_stats`.
+#[repr(C)]
+pub struct PtyStats {
+    pub bytes_read:    u64,
+    pub bytes_written: u64,
+    pub chunks_read:   u64,
+}
+
+/// The child's resource usage, for `bun_pty_get_rusage`.
+#[repr(C)]
+#[derive(Default)]
+pub struct PtyRusage {
+    pub rss_bytes: u64,                        // resident set size
+    pub user_ms:   u64,                        // CPU time in user mode
+    pub system_ms: u64,                        // CPU time in the kernel
+}
+
+/// One buffer of a `bun_pty_writev` scatter list.
+#[repr(C)]
+pub struct IoVec {
+    pub base: *const u8,
+    pub len:  usize,
+}
+
+/// State of the timer thread behind `set_idle_timeout`.
+struct Idle {
+    after:   Option<Duration>,                  // None = off
+    last:    Instant,                           // last output, or when the timeout was set
+    running: bool,
+}
+
+/// Settings for the keepalive thread behind `set_keepalive`.
+#[derive(Default)]
+struct Keepalive {
+    every:   Option<(Duration, Vec<u8>)>,       // interval and probe; None = off
+    running: bool,
+}
+
+/// One entry of a `bun_pty_play_script` script.
+#[derive(Debug, Deserialize)]
+struct ScriptStep {
+    #[serde(default)]
+    delay_ms: u64,                              // after the previous entry, or the start
+    bytes:    String,
+}
+
+/// Token bucket behind `set_read_rate_limit`. A read may overdraw it; reading then
+/// waits until the debt has been paid off at `per_sec`.
+struct RateLimit {
+    per_sec: u64,                               // 0 = unlimited
+    tokens:  f64,                               // bytes that may be read now; negative = in debt
+    at:      Instant,                           // when `tokens` was last topped up
+}
+
+impl RateLimit {
+    fn new(per_sec: u64) -> Self {
+        Self { per_sec, tokens: per_sec as f64 * RATE_BURST.as_secs_f64(), at: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.at).as_secs_f64() * self.per_sec as f64;
+        self.tokens = (self.tokens + earned).min(self.per_sec as f64 * RATE_BURST.as_secs_f64());
+        self.at = now;
+    }
+}
+
+struct Pty {
+    reader: Reader,
+    stderr: Option<Reader>,                     // only with `separate_stderr`
+    tx_w:   Mutex<Option<Sender<WriteMsg>>>,   // dropped on close
+    tx_p:   Mutex<Option<Sender<Vec<u8>>>>,    // priority writes, ahead of everything in `tx_w`
+    master: Arc<Mutex<Box<dyn MasterPty + Send>>>,
+    size:   Mutex<PtySize>,                     // last size applied through `resize`
+    resize_timer: (Mutex<Debounce>, Condvar),
+    keepalive: (Mutex<Keepalive>, Condvar),
+    stale:  AtomicBool,                         // keepalive found the child gone
+    idle:   (Mutex<Idle>, Condvar),
+    idle_killed: AtomicBool,                    // the idle timeout killed the child
+    rate:   Mutex<RateLimit>,                   // `bun_pty_set_read_rate_limit`
+    script: (Mutex<u64>, Condvar),              // number of the playback that may run; bumped to stop it
+    stats:  Counters,
+    spawned: Instant,
+    last_output: AtomicU64,                     // ms after `spawned` of the latest chunk; 0 = none yet
+    config: (Command, SpawnOptions),            // as spawned, for `restart`
+    exe:    String,                             // `cmd` as resolved against cwd and `PATH`
+    has_tty: bool,                              // false when `pipe_fallback` kicked in
+    detached: bool,                             // `detached` took effect: closing doesn't kill
+    scrollback: Mutex<VecDeque<u8>>,            // last `scrollback_bytes` of output
+    scrollback_cap: usize,
+    killer: Arc<Mutex<Box<dyn ChildKiller + Send + Sync>>>,
+    exited: AtomicBool,
+    output_closed: (Mutex<bool>, Condvar),      // read-thread saw EOF/EIO on the master, or there is none
+    in_read: AtomicBool,                        // read-thread is blocked on the master
+    write_gate: Mutex<()>,                      // held for each message the write-thread writes, so a BREAK can't split one
+    end_sent: AtomicBool,
+    linger: Duration,                           // `exit_drain_ms`
+    report_eof: bool,
+    raw_reader: Option<Mutex<Box<dyn Read + Send>>>,   // only with `manage_reads: false`
+    started: Option<Instant>,                   // only with `timestamps`
+    closing: AtomicBool,
+    exit_code: AtomicI32,
+    exit_signal: AtomicI32,
+    reaped: (Mutex<bool>, Condvar),             // set once the wait-thread is done
+    log:    Mutex<Option<io::BufWriter<std::fs::File>>>,   // raw output tee
+    on_data: Mutex<Option<OnData>>,             // replaces the channel while set
+    high_water: Mutex<Option<OnHighWater>>,
+    on_resize: Mutex<Option<OnResize>>,
+    #[cfg(unix)]
+    fg_watch: (Mutex<FgWatch>, Condvar),
+    on_exit: Mutex<(Option<OnExit>, bool)>,     // callback, and whether it has fired
+    subs:   Mutex<Vec<Arc<Subscriber>>>,
+    paused: (Mutex<bool>, Condvar),             // read-thread stops draining the master while set
+    pid:    Option<u32>,
+    #[cfg(unix)]
+    shared: bool,                               // driven by the shared loop and write pool
+    #[cfg(unix)]
+    writer: Mutex<Option<shared::Writer>>,      // `shared` only: the write side, for the pool
+    #[cfg(unix)]
+    write_scheduled: AtomicBool,                // `shared` only: a pool thread has this PTY's writes
+}
+
+unsafe impl Send for Pty {}
+unsafe impl Sync for Pty {}
+
+impl Pty {
+    /// Spawns a new PTY, refusing before anything is started once the handle cap is reached.
+    /// The slot is reserved under `REG`, so concurrent spawns can't all slip past the cap;
+    /// `store` / `store_as` give it back once the handle is in.
+    fn new(cmd: Command, size: PtySize, opts: &SpawnOptions) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        if SHUT_DOWN.load(Ordering::Relaxed) {
+            return Err(Box::new(io::Error::other(SHUT_DOWN_MSG)));
+        }
+        struct Slot;
+        impl Drop for Slot {
+            fn drop(&mut self) { RESERVED.fetch_sub(1, Ordering::Relaxed); }
+        }
+        let slot = {
+            let reg = REG.locked();
+            let max = MAX_HANDLES.load(Ordering::Relaxed);
+            if max > 0 && reg.len() + RESERVED.load(Ordering::Relaxed) >= max { return Err(Box::new(TooManyHandles(max))); }
+            RESERVED.fetch_add(1, Ordering::Relaxed);
+            Slot
+        };
+        let pty = Self::spawn(cmd, size, opts)?;      // a failed or panicking spawn drops `slot`
+        std::mem::forget(slot);                       // kept until the handle is stored
+        Ok(pty)
+    }
+
+    fn spawn(cmd: Command, size: PtySize, opts: &SpawnOptions) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        let config = (cmd.clone(), opts.clone());
+        let cmd = cmd.with_term_env(opts).checked_cwd(opts)?;
+        #[cfg(unix)]
+        let exe = {
+            let p = cmd.resolve_exe()?;
+            p.components().collect::<std::path::PathBuf>().to_string_lossy().into_owned()   // drops `./`
+        };
+        #[cfg(not(unix))]
+        let exe = cmd.cmd.clone();                  // resolved by CreateProcess itself
+
+        let (pty_master, (child, err_pipe), has_tty): (Box<dyn MasterPty + Send>, _, _) = match native_pty_system().openpty(size) {
+            Ok(pair) => {
+                #[cfg(unix)]
+                if opts.raw_mode && let Some(fd) = pair.master.as_raw_fd() {
+                    apply_raw_mode(fd, true);
+                }
+                #[cfg(unix)]
+                let spawned = spawn_direct(&*pair.master, &cmd, opts)?;
+                #[cfg(windows)]
+                let spawned = if !opts.needs_direct() {
+                    (pair.slave.spawn_command(cmd.to_builder())?, None)
+                } else {
+                    spawn_direct(&*pair.master, &cmd, opts)?
+                };
+                drop(pair.slave);                   // the child has its own; ours would keep the master from seeing EOF
+                (pair.master, spawned, true)
+            }
+            Err(e) if opts.pipe_fallback => {
+                debug_as(&opts.label, &format!("openpty failed ({e}), spawning on pipes"));
+                let (m, spawned) = spawn_piped(&cmd, size, opts)?;
+                (Box::new(m), spawned, false)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let killer = Arc::new(Mutex::new(child.clone_killer()));
+        let pid    = child.process_id();
+
+        /* channels */
+        let channel = || match opts.read_channel_capacity {
+            0   => unbounded::<Msg>(),
+            cap => bounded::<Msg>(cap),             // a full channel stalls the read-thread, and so the child
+        };
+        let (tx_r, rx_r)   = channel();
+        let (tx_e, rx_e)   = channel();
+        let (tx_w, rx_w)   = unbounded::<WriteMsg>();
+        let (tx_p, rx_p)   = unbounded::<Vec<u8>>();
+        if !opts.initial_input.is_empty() {
+            let input = opts.initial_input.as_bytes().to_vec();
+            let _ = tx_w.send((input, opts.initial_input.len(), None));   // first in the queue
+        }
+
+        let rdr    = pty_master.try_clone_reader()?;
+        let master = Arc::new(Mutex::new(pty_master));
+        let (rdr, raw_reader) = match opts.manage_reads {
+            true  => (Some(rdr), None),
+            false => (None, Some(Mutex::new(rdr))),
+        };
+
+        let buf_size = opts.read_buffer_size.clamp(READ_BUFFER_RANGE.0, READ_BUFFER_RANGE.1);
+        let quiet_until = Instant::now() + Duration::from_millis(opts.suppress_ms.into());
+        // the shared loop needs a master fd to poll and a pid to reap
+        #[cfg(unix)]
+        let shared_fd = if shared::enabled() && pid.is_some() { master.locked().as_raw_fd() } else { None };
+
+        let pty = Arc::new(Self {
+            reader: Reader::new(rx_r, opts.utf8_chunks),
+            stderr: err_pipe.is_some().then(|| Reader::new(rx_e, opts.utf8_chunks)),
+            tx_w:   Mutex::new(Some(tx_w)),
+            tx_p:   Mutex::new(Some(tx_p)),
+            master: master.clone(),
+            size:   Mutex::new(size),
+            resize_timer: (Mutex::new(Debounce::default()), Condvar::new()),
+            keepalive: (Mutex::new(Keepalive::default()), Condvar::new()),
+            stale:  AtomicBool::new(false),
+            idle:   (Mutex::new(Idle { after: None, last: Instant::now(), running: false }), Condvar::new()),
+            idle_killed: AtomicBool::new(false),
+            rate:   Mutex::new(RateLimit::new(0)),
+            script: (Mutex::new(0), Condvar::new()),
+            stats:  Counters::default(),
+            spawned: Instant::now(),
+            last_output: AtomicU64::new(0),
+            config,
+            exe,
+            has_tty,
+            detached: cfg!(unix) && opts.detached,
+            scrollback: Mutex::new(VecDeque::with_capacity(opts.scrollback_bytes)),
+            scrollback_cap: opts.scrollback_bytes,
+            killer,
+            exited: AtomicBool::new(false),
+            output_closed: (Mutex::new(rdr.is_none()), Condvar::new()),
+            in_read: AtomicBool::new(false),
+            write_gate: Mutex::new(()),
+            end_sent: AtomicBool::new(false),
+            linger: Duration::from_millis(opts.exit_drain_ms),
+            report_eof: opts.report_eof,
+            raw_reader,
+            started: opts.timestamps.then(Instant::now),
+            closing: AtomicBool::new(false),
+            exit_code: AtomicI32::new(-1),
+            exit_signal: AtomicI32::new(0),
+            reaped: (Mutex::new(false), Condvar::new()),
+            log:    Mutex::new(None),
+            on_data: Mutex::new(None),
+            high_water: Mutex::new(None),
+            on_resize: Mutex::new(None),
+            #[cfg(unix)]
+            fg_watch: (Mutex::new(FgWatch::default()), Condvar::new()),
+            on_exit: Mutex::new((None, false)),
+            subs:   Mutex::new(Vec::new()),
+            paused: (Mutex::new(false), Condvar::new()),
+            pid,
+            #[cfg(unix)]
+            shared: shared_fd.is_some(),
+            #[cfg(unix)]
+            writer: Mutex::new(None),
+            #[cfg(unix)]
+            write_scheduled: AtomicBool::new(false),
+        });
+
+        /* shared loop (THREADING_SHARED), in place of the wait- and read-thread */
+        #[cfg(unix)]
+        let (child, rdr) = match (shared_fd, pid) {
+            (Some(fd), Some(pid)) => {
+                shared::adopt(&pty, fd, rdr, (child, pid), tx_r.clone(), buf_size, quiet_until)?;
+                (None, None)
+            }
+            _ => (Some(child), rdr),
+        };
+        #[cfg(not(unix))]
+        let child = Some(child);
+
+        /* wait-thread */
+        if let Some(mut child) = child {
+            let tx = tx_r.clone();
+            let pty_clone = pty.clone();
+            spawn_worker(move || {
+                #[cfg(unix)]
+                if pty_clone.detached && let Some(pid) = pid && !exited_or(pid, &pty_clone.closing) {
+                    return;                         // closed first: the child is on its own now
+                }
+                let (code, signal) = wait_child(&mut child, pid).unwrap_or((-1, 0));
+                pty_clone.set_reaped(code, signal);
+
+                // `End` goes after the last output: once the read-thread hits EOF, or once it
+                // has sat idle on the master for `linger`, as when a background job still
+                // holds the slave open
+                let (lock, cv) = &pty_clone.output_closed;
+                let mut closed = lock.locked();
+                while !*closed && !pty_clone.closing.load(Ordering::Relaxed) {
+                    let (guard, res) = cv.wait_timeout(closed, pty_clone.linger).unwrap_or_else(PoisonError::into_inner);
+                    closed = guard;
+                    if res.timed_out() && pty_clone.in_read.load(Ordering::Relaxed) { break; }
+                }
+                drop(closed);
+                pty_clone.finish(&tx);
+            });
+        }
+
+        /* read-thread */
+        if let Some(mut rdr) = rdr {
+            let tx = tx_r.clone();
+            let pty_clone = pty.clone();
+            #[cfg(unix)]
+            let poll_fd = if pty.detached { master.locked().as_raw_fd() } else { None };
+            spawn_worker(move || {
+                let mut buf = vec![0; buf_size];
+                loop {
+                    pty_clone.wait_unpaused();
+                    pty_clone.wait_read_rate();
+                    pty_clone.in_read.store(true, Ordering::Relaxed);
+                    // a detached child may never write or exit, so don't sit in `read` past a close
+                    #[cfg(unix)]
+                    if let Some(fd) = poll_fd && !readable_or(fd, &pty_clone.closing) { break; }
+                    let res = rdr.read(&mut buf);
+                    pty_clone.in_read.store(false, Ordering::Relaxed);
+                    match res {
+                        Ok(_) if pty_clone.closing.load(Ordering::Relaxed) => break,
+                        Ok(0) | Err(_) => { pty_clone.output_ended(&tx); break; }
+                        Ok(n) => if !pty_clone.take_chunk(&tx, &buf[..n], quiet_until) { break; },
+                    }
+                }
+                if let Some(f) = pty_clone.log.locked().as_mut() { let _ = f.flush(); }
+            });
+        }
+
+        /* stderr-thread (separate_stderr only) */
+        if let Some(mut pipe) = err_pipe {
+            let pty_clone = pty.clone();
+            spawn_worker(move || {
+                let Some(rdr) = &pty_clone.stderr else { return };
+                let mut buf = vec![0; 4096];
+                loop {
+                    match pipe.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            let m = Msg::Data(buf[..n].to_vec(), pty_clone.stamp());
+                            if !pty_clone.send_via(rdr, &tx_e, m) { return; }
+                        }
+                    }
+                }
+                pty_clone.send_via(rdr, &tx_e, Msg::End(0));     // EOF only; the exit code lives on the PTY
+            });
+        }
+
+        /* write-thread  (length-aware)
+           Queued writes go out WRITE_CHUNK bytes at a time, and priority writes are
+           sent before each chunk, so a Ctrl-C doesn't wait behind a large paste. */
+        {
+            let mut wtr = master.locked().take_writer()?;
+            let coalesce = opts.coalesce_writes;
+            if opts.close_stdin {
+                // the EOF goes last in the queue, behind `initial_input`, and nothing may
+                // follow it; a pipe's stdin closes with `wtr`, a PTY has no stdin of its own
+                #[cfg(unix)]
+                let eof = [pty.eof_char()];
+                #[cfg(windows)]
+                let eof = *b"\x1a\r";                // Ctrl-Z, Enter: what console programs take as EOF
+                let tx = pty.tx_w.locked().take();
+                pty.tx_p.locked().take();
+                if pty.has_tty && !opts.raw_mode && let Some(tx) = tx { let _ = tx.send((eof.to_vec(), eof.len(), None)); }
+            }
+            #[cfg(unix)]
+            if pty.shared {
+                *pty.writer.locked() = Some(shared::Writer::new(wtr, rx_p, rx_w, coalesce));
+                pty.kick_writer();                  // for `initial_input`
+                return Ok(pty);
+            }
+            let pty_clone = pty.clone();
+            if opts.close_stdin {
+                spawn_worker(move || {
+                    for m in rx_w.try_iter() {
+                        if !pty_clone.write_queued(&mut wtr, m, &rx_p, &rx_w, false) { break; }
+                    }
+                    // portable-pty's writer sends another `\n` and EOF when dropped, so on a PTY
+                    // it's kept until close, as the write-thread would keep it
+                    if !pty_clone.has_tty { return; }
+                    let (lock, cv) = &pty_clone.output_closed;
+                    let mut g = lock.locked();
+                    while !pty_clone.closing.load(Ordering::Relaxed) {
+                        g = cv.wait(g).unwrap_or_else(PoisonError::into_inner);
+                    }
+                });
+                return Ok(pty);
+            }
+            spawn_worker(move || loop {
+                let ok = select! {
+                    recv(rx_p) -> d => d.is_ok_and(|d| pty_clone.write_now(&mut wtr, &d).1),
+                    recv(rx_w) -> m => m.is_ok_and(|m| pty_clone.write_queued(&mut wtr, m, &rx_p, &rx_w, coalesce)),
+                };
+                if !ok { break; }
+            });
+        }
+
+        Ok(pty)
+    }
+
+    fn read(&self, max: usize) -> Result<Option<Msg>, Box<dyn std::error::Error + Send + Sync>> {
+        let m = self.reader.read(max);
+        if matches!(m, Some(Msg::End(_))) { self.exited.store(true, Ordering::Relaxed); }
+        Ok(m)
+    }
+
+    fn read_plain(&self, max: usize) -> Option<Msg> {
+        let m = self.reader.read_plain(max);
+        if matches!(m, Some(Msg::End(_))) { self.exited.store(true, Ordering::Relaxed); }
+        m
+    }
+
+    fn read_timeout(&self, max: usize, timeout: Option<Duration>) -> Option<Msg> {
+        let m = self.reader.read_timeout(max, timeout);
+        if matches!(m, Some(Msg::End(_))) { self.exited.store(true, Ordering::Relaxed); }
+        m
+    }
+
+    fn read_line(&self, max: usize) -> Result<Option<Msg>, Box<dyn std::error::Error + Send + Sync>> {
+        let m = self.reader.read_line(max);
+        if matches!(m, Some(Msg::End(_))) { self.exited.store(true, Ordering::Relaxed); }
+        Ok(m)
+    }
+
+    fn debug(&self, msg: &str) {
+        debug_as(&self.config.1.label, msg);
+    }
+
+    /// Bookkeeping for every chunk read off the master: stats, and the idle clocks.
+    fn count_read(&self, n: usize) {
+        self.stats.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+        self.stats.chunks_read.fetch_add(1, Ordering::Relaxed);
+        self.last_output.store(self.spawned.elapsed().as_millis() as u64, Ordering::Relaxed);
+        self.idle.0.locked().last = Instant::now();
+    }
+
+    fn get_stats(&self) -> PtyStats {
+        PtyStats {
+            bytes_read:    self.stats.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.stats.bytes_written.load(Ordering::Relaxed),
+            chunks_read:   self.stats.chunks_read.load(Ordering::Relaxed),
+        }
+    }
+
+    fn rusage(&self) -> Result<PtyRusage, c_int> {
+        if self.exited.load(Ordering::Relaxed) { return Err(CHILD_EXITED); }
+        let Some(pid) = self.pid else { return Err(PID_UNAVAILABLE); };
+        proc_rusage(pid).ok_or(ERROR)
+    }
+
+    /// Monotonic nanoseconds since spawn for a chunk read now; 0 without `timestamps`.
+    fn stamp(&self) -> u64 {
+        self.started.map_or(0, |t| t.elapsed().as_nanos() as u64)
+    }
+
+    /// True between the output stream closing and the child being reaped, if asked for.
+    fn eof_pending(&self) -> bool {
+        self.report_eof && *self.output_closed.0.locked() && !self.exited.load(Ordering::Relaxed)
+    }
+
+    /// Handles a chunk read off the master; false once it can't be queued because the
+    /// PTY is closing.
+    fn take_chunk(&self, tx: &Sender<Msg>, data: &[u8], quiet_until: Instant) -> bool {
+        self.count_read(data.len());
+        self.charge_read(data.len());
+        if Instant::now() < quiet_until { return true; }   // `End` comes from `finish`, never dropped here
+        self.tee(data);
+        if self.deliver(data) { return true; }
+        self.send(tx, Msg::Data(data.to_vec(), self.stamp()))
+    }
+
+    /// The master hit EOF or an error: nothing more will be read, so `End` can follow
+    /// as soon as the child is reaped (or right away if it already is).
+    fn output_ended(&self, tx: &Sender<Msg>) {
+        let (lock, cv) = &self.output_closed;
+        *lock.locked() = true;
+        cv.notify_all();
+        if *self.reaped.0.locked() { self.finish(tx); }
+    }
+
+    /// Stores the exit status, wakes whatever waits for the reap and runs the
+    /// on-exit callback.
+    fn set_reaped(&self, code: i32, signal: i32) {
+        self.debug(&format!("exit_status.exit_code(): {code}, signal: {signal}"));
+        self.exit_code.store(code, Ordering::Relaxed);
+        self.exit_signal.store(signal, Ordering::Relaxed);
+        self.exited.store(true, Ordering::Relaxed);
+        let (lock, cv) = &self.reaped;
+        *lock.locked() = true;
+        cv.notify_all();
+        self.fire_on_exit();
+    }
+
+    fn write_now(&self, wtr: &mut Box<dyn Write + Send>, data: &[u8]) -> (usize, bool) {
+        let _gate = self.write_gate.locked();
+        self.write_gated(wtr, data)
+    }
+
+    /// `write_now` for a caller already holding `write_gate`.
+    fn write_gated(&self, wtr: &mut Box<dyn Write + Send>, data: &[u8]) -> (usize, bool) {
+        let (n, ok) = write_counted(wtr, data);
+        self.stats.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+        (n, ok)
+    }
+
+    /// Writes one queued message (with `coalesce`, everything queued behind it too)
+    /// `WRITE_CHUNK` bytes at a time, sending priority writes before each chunk and
+    /// after the last, and acknowledges it. False once the master stops taking data.
+    /// `write_gate` is held throughout, so a BREAK lands before or after the message.
+    fn write_queued(
+        &self,
+        wtr: &mut Box<dyn Write + Send>,
+        m: WriteMsg,
+        rx_p: &Receiver<Vec<u8>>,
+        rx_w: &Receiver<WriteMsg>,
+        coalesce: bool,
+    ) -> bool {
+        let (data, acks) = gather(m, rx_w, coalesce);
+        let gate = self.write_gate.locked();
+        let (mut written, mut ok) = (0, true);
+        for chunk in data.chunks(WRITE_CHUNK) {
+            if !rx_p.try_iter().all(|d| self.write_gated(wtr, &d).1) { ok = false; break; }
+            let (n, chunk_ok) = self.write_gated(wtr, chunk);
+            written += n;
+            if !chunk_ok { ok = false; break; }
+        }
+        // so an acknowledged write, even an empty one from `drain`, has nothing queued
+        // ahead of it left unwritten
+        if ok && !rx_p.try_iter().all(|d| self.write_gated(wtr, &d).1) { ok = false; }
+        drop(gate);
+        settle(acks, written, ok);
+        ok
+    }
+
+    /// Queues `End` with the exit code; the wait- and read-thread may both get here.
+    fn finish(&self, tx: &Sender<Msg>) {
+        if self.end_sent.swap(true, Ordering::SeqCst) { return; }
+        self.send(tx, Msg::End(self.exit_code.load(Ordering::Relaxed)));
+        for s in self.subs.locked().iter() { let _ = s.tx.send(self.end()); }
+    }
+
+    fn end(&self) -> Msg {
+        Msg::End(self.exit_code.load(Ordering::Relaxed))
+    }
+
+    /// Adds a subscriber under `id`; it sees output from now on, and `End` right away
+    /// if that has already gone out.
+    fn subscribe(&self, id: u32) -> Arc<Subscriber> {
+        let (tx, rx) = unbounded();
+        let sub = Arc::new(Subscriber { id, tx, reader: Reader::new(rx, self.config.1.utf8_chunks) });
+        let mut subs = self.subs.locked();
+        if self.end_sent.load(Ordering::SeqCst) || self.closing.load(Ordering::Relaxed) {
+            let _ = sub.tx.send(self.end());
+        }
+        subs.push(sub.clone());
+        sub
+    }
+
+    fn unsubscribe(&self, id: u32) {
+        self.subs.locked().retain(|s| s.id != id);
+    }
+
+    /// Reads straight from the master, blocking until output arrives. `ERROR` if the
+    /// read-thread owns the master.
+    fn raw_read(&self, buf: &mut [u8]) -> c_int {
+        let Some(rdr) = &self.raw_reader else { return ERROR; };
+        match rdr.locked().read(buf) {
+            Ok(0) => CHILD_EXITED,
+            Ok(n) => { self.count_read(n); n as c_int }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+            #[cfg(unix)]
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => CHILD_EXITED,     // Linux: slave side closed
+            Err(_) => ERROR,
+        }
+    }
+
+    fn read_stderr(&self, max: usize) -> Option<Option<Msg>> {
+        self.stderr.as_ref().map(|r| r.read(max))
+    }
+
+    fn send(&self, tx: &Sender<Msg>, m: Msg) -> bool {
+        self.send_via(&self.reader, tx, m)
+    }
+
+    /// Queues `m` for `rdr`, waiting while the channel is full; false once the PTY is closing.
+    fn send_via(&self, rdr: &Reader, tx: &Sender<Msg>, mut m: Msg) -> bool {
+        let n = if let Msg::Data(d, _) = &m { d.len() } else { 0 };
+        rdr.pending.fetch_add(n, Ordering::Relaxed);            // before the reader can see it
+        loop {
+            match tx.send_timeout(m, Duration::from_millis(100)) {
+                Ok(())                             => return true,
+                Err(SendTimeoutError::Timeout(back)) if !self.closing.load(Ordering::Relaxed) => m = back,
+                Err(_) => {
+                    rdr.pending.fetch_sub(n, Ordering::Relaxed);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Parks the read-thread while output is paused, leaving data in the kernel buffer.
+    fn wait_unpaused(&self) {
+        let (lock, cv) = &self.paused;
+        drop(cv.wait_while(lock.locked(), |p| *p && !self.closing.load(Ordering::Relaxed)).unwrap_or_else(PoisonError::into_inner));
+    }
+
+    fn set_paused(&self, paused: bool) {
+        let (lock, cv) = &self.paused;
+        *lock.locked() = paused;
+        cv.notify_all();
+        #[cfg(unix)]
+        if self.shared && !paused { shared::wake(); }
+    }
+
+    /// Runs the on-exit callback, if one is set and none has run yet.
+    fn fire_on_exit(&self) {
+        let mut slot = self.on_exit.locked();
+        if slot.1 { return; }
+        let cb = slot.0.take();
+        slot.1 = cb.is_some();
+        drop(slot);                         // don't hold the lock across the call
+        if let Some(c) = cb {
+            (c.cb)(c.handle, self.exit_code.load(Ordering::Relaxed), self.exit_signal.load(Ordering::Relaxed), c.user);
+        }
+    }
+
+    /// Hands `data` to the on-data callback, if one is set (called on the read-thread).
+    fn deliver(&self, data: &[u8]) -> bool {
+        let cb = *self.on_data.locked();    // don't hold the lock across the call
+        match cb {
+            Some(c) => { (c.cb)(c.handle, data.as_ptr(), data.len(), c.user); true }
+            None    => false,
+        }
+    }
+
+    fn tee(&self, data: &[u8]) {
+        let mut log = self.log.locked();
+        if let Some(f) = log.as_mut() && f.write_all(data).is_err() { *log = None; }
+        drop(log);
+        self.remember(data);
+        let subs = self.subs.locked();
+        if subs.is_empty() { return; }
+        let ts = self.stamp();
+        for s in subs.iter() {
+            s.reader.pending.fetch_add(data.len(), Ordering::Relaxed);
+            let _ = s.tx.send(Msg::Data(data.to_vec(), ts));
+        }
+    }
+
+    /// Appends to the scrollback ring, dropping the oldest bytes past `scrollback_cap`.
+    fn remember(&self, data: &[u8]) {
+        if self.scrollback_cap == 0 { return; }
+        let data = &data[data.len().saturating_sub(self.scrollback_cap)..];
+        let mut ring = self.scrollback.locked();
+        let over = (ring.len() + data.len()).saturating_sub(self.scrollback_cap);
+        ring.drain(..over);
+        ring.extend(data);
+    }
+
+    /// Copies the most recent scrollback, up to `buf.len()` bytes, into `buf`.
+    fn scrollback(&self, buf: &mut [u8]) -> usize {
+        let ring = self.scrollback.locked();
+        let n = ring.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(ring.range(ring.len() - n..)) { *dst = *src; }
+        n
+    }
+
+    fn enable_logging(&self, path: &str) -> c_int {
+        match std::fs::File::create(path) {
+            Ok(f)  => { *self.log.locked() = Some(io::BufWriter::new(f)); SUCCESS }
+            Err(e) => { self.debug(&format!("log open error: {e}")); ERROR }
+        }
+    }
+
+    /// Flushes and closes the log, if any.
+    fn disable_logging(&self) {
+        if let Some(mut f) = self.log.locked().take() { let _ = f.flush(); }
+    }
+
+    fn write(self: &Arc<Self>, data: *const u8, len: usize) -> c_int {
+        let slice = unsafe { std::slice::from_raw_parts(data, len) };
+        self.enqueue(slice.to_vec())
+    }
+
+    /// Sends `data` ahead of everything already queued, between chunks of a write in progress.
+    fn write_priority(self: &Arc<Self>, data: Vec<u8>) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let before = self.queue_len();
+        let sent = self.tx_p.locked().as_ref().map(|tx| tx.send(data));     // lock released before the check
+        match sent {
+            Some(Ok(_)) => { self.kick_writer(); self.check_high_water(before); SUCCESS }
+            _           => ERROR,
+        }
+    }
+
+    /// Queues `data` for the write-thread without waiting for it to be written.
+    fn enqueue(self: &Arc<Self>, data: Vec<u8>) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let len = data.len();
+        let before = self.queue_len();
+        let sent = self.tx_w.locked().as_ref().map(|tx| tx.send((data, len, None)));
+        match sent {
+            Some(Ok(_)) => { self.kick_writer(); self.check_high_water(before); SUCCESS }
+            _           => ERROR,
+        }
+    }
+
+    /// With `THREADING_SHARED`, gets a pool thread onto the queued writes, unless one
+    /// already has them.
+    fn kick_writer(self: &Arc<Self>) {
+        #[cfg(unix)]
+        if self.shared && !self.write_scheduled.swap(true, Ordering::SeqCst) { shared::schedule(self.clone()); }
+    }
+
+    /// Writes queued and not yet picked up by the write-thread, priority ones included.
+    fn queue_len(&self) -> usize {
+        self.tx_w.locked().as_ref().map_or(0, |tx| tx.len()) + self.tx_p.locked().as_ref().map_or(0, |tx| tx.len())
+    }
+
+    /// Calls the high-water callback if the write just queued took the backlog from
+    /// under the mark to at or over it. Runs on the thread that queued the write, not
+    /// the write-thread.
+    fn check_high_water(&self, before: usize) {
+        let Some(h) = *self.high_water.locked() else { return };
+        let after = self.queue_len();
+        if before < h.mark && after >= h.mark { (h.cb)(h.handle, after as c_int, h.user); }
+    }
+
+    /// Like `write`, but waits for the write-thread to get to it and returns how many
+    /// bytes the master accepted (fewer if the write failed partway).
+    fn write_sync(self: &Arc<Self>, data: *const u8, len: usize) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let slice = unsafe { std::slice::from_raw_parts(data, len) };
+        let (ack, done) = bounded(1);
+        let queued = self.tx_w.locked().as_ref().map(|tx| tx.send((slice.to_vec(), len, Some(ack))));
+        if !matches!(queued, Some(Ok(_))) { return ERROR; }
+        self.kick_writer();
+        done.recv().unwrap_or(ERROR)                  // write-thread gone before getting to it
+    }
+
+    /// A fresh PTY running the same command with the same options, at the current size.
+    /// It takes over an existing handle, so the handle cap doesn't apply.
+    fn respawn(&self) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        let (cmd, opts) = &self.config;
+        Self::spawn(cmd.clone(), *self.size.locked(), opts)
+    }
+
+    /// Waits until everything written so far has gone through the write-thread and been
+    /// flushed, by queueing an empty write and waiting for its acknowledgement.
+    fn drain(self: &Arc<Self>, timeout: Option<Duration>) -> c_int {
+        let (ack, done) = bounded(1);
+        let queued = self.tx_w.locked().as_ref().map(|tx| tx.send((Vec::new(), 0, Some(ack))));
+        if !matches!(queued, Some(Ok(_))) { return ERROR; }
+        self.kick_writer();
+        let res = match timeout {
+            Some(t) => done.recv_timeout(t).map_err(|e| e.is_timeout()),
+            None    => done.recv().map_err(|_| false),
+        };
+        match res {
+            Ok(n) if n >= 0 => SUCCESS,
+            Err(true)       => TIMEOUT,
+            _               => ERROR,
+        }
+    }
+
+    /// Kills a still-running child and releases everything the threads are blocked on:
+    /// dropping `tx_w` ends the write-thread, and `closing` stops the read-thread from
+    /// waiting on a full channel and the wait-thread from waiting on the read-thread.
+    /// The callbacks are taken first, so none is picked up once this returns; one a
+    /// thread has already picked up may still be running.
+    fn shutdown(&self) {
+        self.closing.store(true, Ordering::Relaxed);
+        self.on_data.locked().take();
+        self.on_exit.locked().0.take();
+        self.high_water.locked().take();
+        self.on_resize.locked().take();
+        self.set_paused(false);
+        if !self.exited.load(Ordering::Relaxed) && !self.detached { self.kill(); }
+        self.tx_w.locked().take();
+        self.tx_p.locked().take();
+        self.disable_logging();
+        // the timer threads (and a wait-thread waiting on output) are either waiting or about to see `closing`
+        drop(self.output_closed.0.locked());
+        self.output_closed.1.notify_all();
+        drop(self.resize_timer.0.locked());
+        self.resize_timer.1.notify_all();
+        drop(self.keepalive.0.locked());
+        self.keepalive.1.notify_all();
+        drop(self.idle.0.locked());
+        self.idle.1.notify_all();
+        drop(self.script.0.locked());
+        self.script.1.notify_all();
+        #[cfg(unix)]
+        { self.fg_watch.0.locked().cb.take(); self.fg_watch.1.notify_all(); }
+        // no `End` will come from `finish` now; subscribers still get one
+        for s in self.subs.locked().drain(..) { let _ = s.tx.send(self.end()); }
+        #[cfg(unix)]
+        if self.shared {
+            shared::wake();                         // stop polling it
+            if let Ok(mut w) = self.writer.try_lock() { w.take(); }   // else the pool drops it when done
+        }
+    }
+
+    /// Blocks until the child is reaped; `None` waits forever.
+    fn wait(&self, timeout: Option<Duration>) -> c_int {
+        let (lock, cv) = &self.reaped;
+        let done = lock.locked();
+        let done = match timeout {
+            Some(t) => cv.wait_timeout_while(done, t, |d| !*d).unwrap_or_else(PoisonError::into_inner).0,
+            None    => cv.wait_while(done, |d| !*d).unwrap_or_else(PoisonError::into_inner),
+        };
+        if *done { self.exit_code.load(Ordering::Relaxed) } else { TIMEOUT }
+    }
+
+    /// `cols` by `rows` at the pixel size last applied, for the resizes that only take cells.
+    fn cells(&self, cols: u16, rows: u16) -> PtySize {
+        PtySize { cols, rows, ..*self.size.locked() }
+    }
+
+    /// Applies `size` now, dropping any debounced resize still waiting.
+    fn resize(&self, size: PtySize) -> c_int {
+        self.resize_timer.0.locked().pending = None;
+        self.apply_size(size)
+    }
+
+    /// Applies `size` once no further call has come in for `delay`, so a burst of
+    /// resizes costs the child a single SIGWINCH. The timer thread starts on first use.
+    fn resize_debounced(self: &Arc<Self>, size: PtySize, delay: Duration) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let (lock, cv) = &self.resize_timer;
+        let mut d = lock.locked();
+        d.pending = Some((size, Instant::now() + delay));
+        if !d.running {
+            d.running = true;
+            let pty = self.clone();
+            spawn_worker(move || pty.run_resize_timer());
+        }
+        cv.notify_all();
+        SUCCESS
+    }
+
+    fn run_resize_timer(&self) {
+        let (lock, cv) = &self.resize_timer;
+        let mut d = lock.locked();
+        while !self.closing.load(Ordering::Relaxed) {
+            match d.pending {
+                None => d = cv.wait(d).unwrap_or_else(PoisonError::into_inner),
+                Some((_, due)) if Instant::now() < due => {
+                    d = cv.wait_timeout(d, due.saturating_duration_since(Instant::now())).unwrap_or_else(PoisonError::into_inner).0;
+                }
+                Some((size, _)) => {
+                    d.pending = None;
+                    drop(d);                        // `resize` may come in meanwhile
+                    self.apply_size(size);
+                    d = lock.locked();
+                }
+            }
+        }
+    }
+
+    /// Writes `probe` every `interval` while the child lives; once it's found gone the
+    /// PTY is marked stale and probing stops. A zero interval turns it off.
+    fn set_keepalive(self: &Arc<Self>, interval: Duration, probe: Vec<u8>) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let (lock, cv) = &self.keepalive;
+        let mut k = lock.locked();
+        k.every = (!interval.is_zero()).then_some((interval, probe));
+        if k.every.is_some() && !k.running {
+            k.running = true;
+            let pty = self.clone();
+            spawn_worker(move || pty.run_keepalive());
+        }
+        cv.notify_all();
+        SUCCESS
+    }
+
+    fn run_keepalive(self: &Arc<Self>) {
+        let (lock, cv) = &self.keepalive;
+        let mut k = lock.locked();
+        while !self.closing.load(Ordering::Relaxed) {
+            let Some((every, _)) = k.every else {
+                k = cv.wait(k).unwrap_or_else(PoisonError::into_inner);
+                continue;
+            };
+            let (guard, res) = cv.wait_timeout(k, every).unwrap_or_else(PoisonError::into_inner);
+            k = guard;
+            if !res.timed_out() { continue; }           // reconfigured: start the interval over
+            let Some((_, probe)) = &k.every else { continue; };
+            if !self.child_alive() {
+                self.stale.store(true, Ordering::Relaxed);
+                break;
+            }
+            let probe = probe.clone();
+            drop(k);                                    // `enqueue` may call the high-water callback
+            self.enqueue(probe);                        // which also gets the shared pool onto it
+            k = lock.locked();
+        }
+        k.running = false;
+    }
+
+    /// Kills the child once it has gone `timeout` without output; zero turns it off.
+    /// The clock starts over now, so setting it is never an immediate kill.
+    fn set_idle_timeout(self: &Arc<Self>, timeout: Duration) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let (lock, cv) = &self.idle;
+        let mut t = lock.locked();
+        t.after = (!timeout.is_zero()).then_some(timeout);
+        t.last = Instant::now();
+        if t.after.is_some() && !t.running {
+            t.running = true;
+            let pty = self.clone();
+            spawn_worker(move || pty.run_idle_timer());
+        }
+        cv.notify_all();
+        SUCCESS
+    }
+
+    fn run_idle_timer(&self) {
+        let (lock, cv) = &self.idle;
+        let mut t = lock.locked();
+        while !self.closing.load(Ordering::Relaxed) && !self.exited.load(Ordering::Relaxed) {
+            let Some(after) = t.after else {
+                t = cv.wait(t).unwrap_or_else(PoisonError::into_inner);
+                continue;
+            };
+            let left = (t.last + after).saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                self.debug(&format!("no output for {after:?}, killing child"));
+                self.idle_killed.store(true, Ordering::Relaxed);
+                if self.kill() != SUCCESS { self.idle_killed.store(false, Ordering::Relaxed); }
+                break;
+            }
+            t = cv.wait_timeout(t, left).unwrap_or_else(PoisonError::into_inner).0;
+        }
+        t.running = false;
+    }
+
+    fn set_read_rate_limit(&self, per_sec: u64) -> c_int {
+        *self.rate.locked() = RateLimit::new(per_sec);
+        #[cfg(unix)]
+        if self.shared { shared::wake(); }
+        SUCCESS
+    }
+
+    /// Counts `n` bytes just read against the rate limit.
+    fn charge_read(&self, n: usize) {
+        let mut r = self.rate.locked();
+        if r.per_sec == 0 { return; }
+        r.refill();
+        r.tokens -= n as f64;
+    }
+
+    /// How long the rate limit holds off the next read; zero if it may go ahead.
+    fn read_delay(&self) -> Duration {
+        let mut r = self.rate.locked();
+        if r.per_sec == 0 { return Duration::ZERO; }
+        r.refill();
+        if r.tokens >= 0.0 { return Duration::ZERO; }
+        Duration::from_secs_f64(-r.tokens / r.per_sec as f64)
+    }
+
+    /// Sleeps off `read_delay`, a tick at a time so a close or a new limit is noticed.
+    fn wait_read_rate(&self) {
+        loop {
+            let d = self.read_delay();
+            if d.is_zero() || self.closing.load(Ordering::Relaxed) { return; }
+            thread::sleep(d.min(Duration::from_millis(100)));
+        }
+    }
+
+    /// Replays `steps` into the write queue from a helper thread, stopping whatever
+    /// script was playing before.
+    fn play_script(self: &Arc<Self>, steps: Vec<ScriptStep>) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let id = self.stop_script();
+        let pty = self.clone();
+        spawn_worker(move || pty.run_script(id, steps));
+        SUCCESS
+    }
+
+    /// Stops the running script, if any; returns the number the next one plays under.
+    fn stop_script(&self) -> u64 {
+        let (lock, cv) = &self.script;
+        let mut cur = lock.locked();
+        *cur += 1;
+        cv.notify_all();
+        *cur
+    }
+
+    fn run_script(self: &Arc<Self>, id: u64, steps: Vec<ScriptStep>) {
+        let (lock, cv) = &self.script;
+        for step in steps {
+            let due = Instant::now() + Duration::from_millis(step.delay_ms);
+            let mut cur = lock.locked();
+            loop {
+                if *cur != id || self.closing.load(Ordering::Relaxed) { return; }
+                let left = due.saturating_duration_since(Instant::now());
+                if left.is_zero() { break; }
+                cur = cv.wait_timeout(cur, left).unwrap_or_else(PoisonError::into_inner).0;
+            }
+            drop(cur);
+            if self.enqueue(step.bytes.into_bytes()) != SUCCESS { return; }
+        }
+    }
+
+    /// Whether the child still exists, asking the OS rather than waiting for the reap.
+    fn child_alive(&self) -> bool {
+        if self.exited.load(Ordering::Relaxed) { return false; }
+        #[cfg(unix)]
+        if let Some(pid) = self.pid && unsafe { libc::kill(pid as libc::pid_t, 0) } != 0 {
+            return io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+        }
+        true
+    }
+
+    fn apply_size(&self, size: PtySize) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let res = self.master.locked().resize(size);
+        if res.is_err() { return ERROR; }
+        *self.size.locked() = size;
+        let cb = *self.on_resize.locked();      // don't hold the lock across the call
+        if let Some(c) = cb {
+            (c.cb)(c.handle, size.cols as c_int, size.rows as c_int, c.user);
+        }
+        SUCCESS
+    }
+
+    #[cfg(unix)]
+    fn set_raw_mode(&self, raw: bool) -> c_int {
+        match self.master.locked().as_raw_fd() {
+            Some(fd) => apply_raw_mode(fd, raw),
+            None     => ERROR,
+        }
+    }
+
+    #[cfg(windows)]
+    fn set_raw_mode(&self, _raw: bool) -> c_int { ERROR }
+
+    /// The kernel's view of the size where available, else the last applied one.
+    fn get_size(&self) -> PtySize {
+        self.master.locked().get_size().unwrap_or_else(|_| *self.size.locked())
+    }
+
+    /// The line discipline's EOF character (VEOF), or Ctrl-D where it can't be read.
+    #[cfg(unix)]
+    fn eof_char(&self) -> u8 {
+        let mut t: libc::termios = unsafe { std::mem::zeroed() };
+        let fd = self.master_fd();
+        if fd < 0 || unsafe { libc::tcgetattr(fd, &mut t) } != 0 { return 0x04; }
+        match t.c_cc[libc::VEOF] {
+            0 => 0x04,                                // disabled (_POSIX_VDISABLE)
+            c => c,
+        }
+    }
+
+    #[cfg(windows)]
+    fn eof_char(&self) -> u8 { 0x04 }
+
+    #[cfg(unix)]
+    fn master_fd(&self) -> c_int {
+        self.master.locked().as_raw_fd().unwrap_or(ERROR)
+    }
+
+    #[cfg(windows)]
+    fn master_fd(&self) -> c_int { ERROR }
+
+    /// Sends a BREAK (`tcsendbreak`) once everything written so far has gone out. The
+    /// gate (the writer's lock, in shared mode) keeps it out of any message started since.
+    #[cfg(unix)]
+    fn send_break(self: &Arc<Self>, duration_ms: c_int) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let fd = self.master_fd();
+        if fd < 0 { return ERROR; }
+        let drained = self.drain(None);
+        if drained != SUCCESS { return drained; }
+        let brk = || if unsafe { libc::tcdrain(fd) == 0 && libc::tcsendbreak(fd, duration_ms) == 0 } { SUCCESS } else { ERROR };
+        if self.shared { return shared::between_messages(self, brk); }
+        let _gate = self.write_gate.locked();
+        brk()
+    }
+
+    #[cfg(windows)]
+    fn send_break(self: &Arc<Self>, _duration_ms: c_int) -> c_int { ERROR }
+
+    #[cfg(unix)]
+    fn tty_path(&self) -> Option<std::path::PathBuf> {
+        self.master.locked().as_raw_fd().and_then(tty_name)
+    }
+
+    #[cfg(windows)]
+    fn tty_path(&self) -> Option<std::path::PathBuf> { None }
+
+    #[cfg(unix)]
+    fn signal(&self, signum: c_int) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let Some(pid) = self.pid else { return PID_UNAVAILABLE; };
+        if unsafe { libc::kill(pid as libc::pid_t, signum) } == 0 { return SUCCESS; }
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::ESRCH) => CHILD_EXITED,
+            _                 => ERROR,
+        }
+    }
+
+    /// Windows has no POSIX signals; only the terminating ones map onto `kill`.
+    #[cfg(windows)]
+    fn signal(&self, signum: c_int) -> c_int {
+        const SIGINT: c_int = 2; const SIGKILL: c_int = 9; const SIGTERM: c_int = 15;
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        match signum {
+            SIGINT | SIGKILL | SIGTERM => self.kill(),
+            _                          => ERROR,
+        }
+    }
+
+    /// The child's process group; it leads its own session, so normally its PID.
+    #[cfg(unix)]
+    fn pgid(&self) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let Some(pid) = self.pid else { return PID_UNAVAILABLE; };
+        match unsafe { libc::getpgid(pid as libc::pid_t) } {
+            -1   => ERROR,
+            pgid => pgid,
+        }
+    }
+
+    #[cfg(windows)]
+    fn pgid(&self) -> c_int { ERROR }
+
+    /// The terminal's foreground process group (`tcgetpgrp` on the master); the shell's
+    /// own group at the prompt, the command's while one runs.
+    #[cfg(unix)]
+    fn foreground_pgid(&self) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        self.master.locked().process_group_leader().unwrap_or(ERROR)
+    }
+
+    #[cfg(windows)]
+    fn foreground_pgid(&self) -> c_int { ERROR }
+
+    /// Calls `cb` whenever `foreground_pgid` changes, from a thread that polls it every
+    /// `FG_POLL`; `None` pauses the thread. Changes are counted from now.
+    #[cfg(unix)]
+    fn set_on_fg_change(self: &Arc<Self>, cb: Option<OnFgChange>) -> c_int {
+        if self.exited.load(Ordering::Relaxed) { return CHILD_EXITED; }
+        let (lock, cv) = &self.fg_watch;
+        let mut w = lock.locked();
+        w.cb = cb;
+        w.last = self.foreground_pgid();
+        if w.cb.is_some() && !w.running {
+            w.running = true;
+            let pty = self.clone();
+            spawn_worker(move || pty.run_fg_watch());
+        }
+        cv.notify_all();
+        SUCCESS
+    }
+
+    #[cfg(windows)]
+    fn set_on_fg_change(self: &Arc<Self>, _cb: Option<OnFgChange>) -> c_int { ERROR }
+
+    #[cfg(unix)]
+    fn run_fg_watch(&self) {
+        let (lock, cv) = &self.fg_watch;
+        let mut w = lock.locked();
+        while !self.closing.load(Ordering::Relaxed) && !self.exited.load(Ordering::Relaxed) {
+            if w.cb.is_none() {
+                w = cv.wait(w).unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+            w = cv.wait_timeout(w, FG_POLL).unwrap_or_else(PoisonError::into_inner).0;
+            let Some(c) = w.cb else { continue };
+            let pgid = self.foreground_pgid();
+            if pgid < 0 || pgid == w.last { continue; }
+            w.last = pgid;
+            drop(w);                                // don't hold the lock across the call
+            (c.cb)(c.handle, pgid, c.user);
+            w = lock.locked();
+        }
+        w.running = false;
+    }
+
+    /// Like `signal`, but for every process in the child's group.
+    #[cfg(unix)]
+    fn signal_group(&self, signum: c_int) -> c_int {
+        let pgid = self.pgid();
+        if pgid < 0 { return pgid; }
+        if unsafe { libc::kill(-pgid, signum) } == 0 { return SUCCESS; }
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::ESRCH) => CHILD_EXITED,
+            _                 => ERROR,
+        }
+    }
+
+    /// No process groups on Windows; the child alone is signalled.
+    #[cfg(windows)]
+    fn signal_group(&self, signum: c_int) -> c_int { self.signal(signum) }
+
+    /// Asks the child to exit (`SIGTERM`; Ctrl-C on Windows, which has no signals),
+    /// waits up to `grace` for the reap, then kills it if it is still there.
+    fn terminate(self: &Arc<Self>, grace: Duration) -> c_int {
+        if *self.reaped.0.locked() { return CHILD_EXITED; }
+        #[cfg(unix)]
+        let asked = self.signal(libc::SIGTERM);
+        #[cfg(windows)]
+        let asked = self.write_priority(vec![0x03]);   // ConPTY raises CTRL_C_EVENT for it
+        if !matches!(asked, ERROR | PID_UNAVAILABLE) && self.wait(Some(grace)) != TIMEOUT { return TERMINATED; }
+        match self.kill() {
+            SUCCESS => FORCE_KILLED,
+            e       => e,
+        }
+    }
+
+    fn kill(&self) -> c_int {
+        match self.killer.locked().kill() {
+            Ok(_)  => { self.exited.store(true, Ordering::Relaxed); SUCCESS }
+            Err(_) => ERROR,
+        }
+    }
+}
+
+/* ---------- registry ---------- */
+
+use std::sync::atomic::AtomicU32;
+type SubEntry = (Arc<Pty>, Arc<Subscriber>);                // sub-handle: its PTY, and its own stream
+lazy_static::lazy_static! {
+    static ref REG: Mutex<HashMap<u32, Arc<Pty>>> = Mutex::new(HashMap::new());
+    static ref CLOSED: Mutex<VecDeque<u32>> = Mutex::new(VecDeque::new());   // recent closes, oldest first
+    static ref SUBS: Mutex<HashMap<u32, SubEntry>> = Mutex::new(HashMap::new());
+}
+static NEXT: AtomicU32 = AtomicU32::new(1);
+static MAX_HANDLES: AtomicUsize = AtomicUsize::new(0);      // 0 = no limit
+static RESERVED: AtomicUsize = AtomicUsize::new(0);         // spawned by `Pty::new`, not stored yet
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);       // between `bun_pty_shutdown` and `bun_pty_init`; set under `REG`
+const SHUT_DOWN_MSG: &str = "library is shut down; call bun_pty_init first";
+const CLOSED_CAP: usize = 1024;
+
+/* handle states (bun_pty_handle_state) */
+const HANDLE_UNKNOWN: c_int = 0;
+const HANDLE_ALIVE: c_int   = 1;
+const HANDLE_EXITED: c_int  = 2;
+const HANDLE_CLOSED: c_int  = 3;
+
+/// Registers a PTY from `Pty::new`, releasing the slot it reserved, and returns its
+/// handle. `SHUT_DOWN` is checked under `REG`, so a spawn that was already past the
+/// check in `Pty::new` when `bun_pty_shutdown` ran is closed here instead.
+fn store(pty: Arc<Pty>) -> c_int {
+    let mut reg = REG.locked();
+    RESERVED.fetch_sub(1, Ordering::Relaxed);
+    if SHUT_DOWN.load(Ordering::Relaxed) { drop(reg); return shut_out(pty); }
+    let id = fresh_id(&reg);
+    reg.insert(id, pty);
+    id as c_int
+}
+/// Closes a PTY spawned across `bun_pty_shutdown`, failing the spawn as `Pty::new` would.
+fn shut_out(pty: Arc<Pty>) -> c_int {
+    pty.shutdown();
+    spawn_failed(&pty.config.1.label, &io::Error::other(SHUT_DOWN_MSG))
+}
+/// Next ID free for a handle or sub-handle; call with `REG` held.
+fn fresh_id(reg: &HashMap<u32, Arc<Pty>>) -> u32 {
+    let subs = SUBS.locked();
+    loop {
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        if !reg.contains_key(&id) && !subs.contains_key(&id) { break id; }   // skip IDs taken via `store_as`
+    }
+}
+/// `store` under a caller-chosen ID; if it's taken the PTY is closed and
+/// `ERR_HANDLE_IN_USE` returned.
+fn store_as(id: u32, pty: Arc<Pty>) -> c_int {
+    let mut reg = REG.locked();
+    RESERVED.fetch_sub(1, Ordering::Relaxed);
+    if SHUT_DOWN.load(Ordering::Relaxed) { drop(reg); return shut_out(pty); }
+    if reg.contains_key(&id) || SUBS.locked().contains_key(&id) {
+        drop(reg);
+        pty.shutdown();                               // lost a race for the ID
+        return ERR_HANDLE_IN_USE;
+    }
+    reg.insert(id, pty);
+    CLOSED.locked().retain(|&c| c != id);    // alive again
+    id as c_int
+}
+fn remove(id: u32) -> Option<Arc<Pty>> {
+    let pty = REG.locked().remove(&id)?;
+    tombstone(id);
+    Some(pty)
+}
+/// Empties the registry, handing back everything that was in it; sub-handles just go.
+fn remove_all() -> Vec<Arc<Pty>> {
+    let all: Vec<_> = REG.locked().drain().collect();
+    for (id, _) in SUBS.locked().drain() { tombstone(id); }
+    all.into_iter().map(|(id, pty)| { tombstone(id); pty }).collect()
+}
+/// Creates a sub-handle on `pty`; none once `bun_pty_shutdown` has run.
+fn add_sub(pty: Arc<Pty>) -> Option<u32> {
+    let reg = REG.locked();
+    if SHUT_DOWN.load(Ordering::Relaxed) { return None; }
+    let id = fresh_id(&reg);
+    let sub = pty.subscribe(id);
+    SUBS.locked().insert(id, (pty, sub));
+    Some(id)
+}
+fn remove_sub(id: u32) -> bool {
+    let Some((pty, _)) = SUBS.locked().remove(&id) else { return false };
+    pty.unsubscribe(id);
+    tombstone(id);
+    true
+}
+fn get_sub(id: u32) -> Option<Arc<Subscriber>> {
+    SUBS.locked().get(&id).map(|(_, s)| s.clone())
+}
+fn tombstone(id: u32) {
+    let mut closed = CLOSED.locked();
+    if closed.len() == CLOSED_CAP { closed.pop_front(); }
+    closed.push_back(id);
+}
+/// Swaps the PTY behind an open handle, handing back the old one; gives `pty` back
+/// if the handle was closed in the meantime.
+fn replace(id: u32, pty: Arc<Pty>) -> Result<Arc<Pty>, Arc<Pty>> {
+    match REG.locked().get_mut(&id) {
+        Some(slot) => Ok(std::mem::replace(slot, pty)),
+        None       => Err(pty),
+    }
+}
+/// Clones the handle out so long-running calls don't hold `REG`.
+fn get(id: u32) -> Option<Arc<Pty>> {
+    REG.locked().get(&id).cloned()
+}
+/// Runs `f` on the handle without holding `REG`, so a slow call on one PTY
+/// (e.g. the exit drain in `read`) doesn't stall calls on the others.
+fn with<F: FnOnce(&Arc<Pty>) -> c_int>(id: u32, f: F) -> c_int {
+    get(id).map(|p| f(&p)).unwrap_or(ERROR)
+}
+/// `with` for the channel-backed reads, which have nothing to return in manual mode.
+fn with_managed<F: FnOnce(&Arc<Pty>) -> c_int>(id: u32, f: F) -> c_int {
+    with(id, |p| if p.raw_reader.is_some() { MANUAL_READS } else { f(p) })
+}
+
+/* ---------- FFI ---------- */
+
+/// Runs an export's body, turning a panic into `fallback` rather than letting it
+/// reach the `extern "C"` boundary, where it would abort the host process.
+fn ffi_guard<R>(fallback: R, f: impl FnOnce() -> R) -> R {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        debug("panic caught at the FFI boundary");
+        fallback
+    })
+}
+
+/// Reads `count` C strings from `ptrs`; `None` if any entry is null.
+unsafe fn cstr_array(ptrs: *const *const c_char, count: c_int) -> Option<Vec<String>> {
+    if count == 0 { return Some(Vec::new()); }
+    if ptrs.is_null() || count < 0 { return None; }
+    let raw = unsafe { std::slice::from_raw_parts(ptrs, count as usize) };
+    raw.iter()
+        .map(|&p| (!p.is_null()).then(|| unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned()))
+        .collect()
+}
+
+/// Shell-splits `cmd` and spawns it. Returns `ERR_INVALID_CMDLINE` if it doesn't split
+/// (e.g. `echo "unterminated`); the other spawn calls taking a command line do the same.
+///
+/// # Safety
+/// `cmd` and `cwd` must be valid NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_spawn(
+    cmd:  *const c_char,
+    cwd:  *const c_char,
+    cols: c_int,
+    rows: c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if cmd.is_null() || cwd.is_null() || cols <= 0 || rows <= 0 { return ERROR; }
+
+        let cmdline = unsafe { CStr::from_ptr(cmd) }.to_string_lossy();
+        let cwd     = unsafe { CStr::from_ptr(cwd) }.to_string_lossy();
+
+        let cmd = match Command::from_cmdline(&cmdline, &cwd) {
+            Ok(c)  => c,
+            Err(e) => { spawn_failed("", &e); return ERR_INVALID_CMDLINE; }
+        };
+        let size = PtySize { cols: cols as u16, rows: rows as u16, pixel_width: 0, pixel_height: 0 };
+        match Pty::new(cmd, size, &SpawnOptions::default()) {
+            Ok(p)  => store(p),
+            Err(e) => spawn_failed("", &*e),
+        }
+    })
+}
+
+/// Spawns the user's default shell: `$SHELL` (falling back to the passwd entry, then
+/// `/bin/sh`) on Unix, `%COMSPEC%` on Windows. A non-zero `login` adds `-l` on Unix.
+///
+/// # Safety
+/// `cwd` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_spawn_shell(cwd: *const c_char, cols: c_int, rows: c_int, login: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if cwd.is_null() || cols <= 0 || rows <= 0 { return ERROR; }
+        let cwd  = unsafe { CStr::from_ptr(cwd) }.to_string_lossy();
+        let cmd  = Command::default_shell(&cwd, login != 0);
+        let size = PtySize { cols: cols as u16, rows: rows as u16, pixel_width: 0, pixel_height: 0 };
+        match Pty::new(cmd, size, &SpawnOptions::default()) {
+            Ok(p)  => store(p),
+            Err(e) => spawn_failed("", &*e),
+        }
+    })
+}
+
+/// Like `bun_pty_spawn`, but takes the argv verbatim instead of shell-splitting a command line.
+///
+/// # Safety
+/// `argv` must point to `argc` valid NUL-terminated strings; `cwd` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_spawn_ex(
+    argv: *const *const c_char,
+    argc: c_int,
+    cwd:  *const c_char,
+    cols: c_int,
+    rows: c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if argc <= 0 || cwd.is_null() || cols <= 0 || rows <= 0 { return ERROR; }
+
+        let Some(argv) = (unsafe { cstr_array(argv, argc) }) else { return ERROR; };
+        let cwd = unsafe { CStr::from_ptr(cwd) }.to_string_lossy();
+
+        let size = PtySize { cols: cols as u16, rows: rows as u16, pixel_width: 0, pixel_height: 0 };
+        match Pty::new(Command::from_argv(&argv, &cwd), size, &SpawnOptions::default()) {
+            Ok(p)  => store(p),
+            Err(e) => spawn_failed("", &*e),
+        }
+    })
+}
+
+/// Like `bun_pty_spawn`, but the child gets only the `env_count` key/value pairs given
+/// instead of inheriting the host environment. `env_count == 0` spawns with an empty env.
+///
+/// # Safety
+/// `cmd` and `cwd` must be valid NUL-terminated strings; `env_keys` and `env_vals` must each
+/// point to `env_count` valid NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_spawn_with_env(
+    cmd:       *const c_char,
+    cwd:       *const c_char,
+    cols:      c_int,
+    rows:      c_int,
+    env_keys:  *const *const c_char,
+    env_vals:  *const *const c_char,
+    env_count: c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if cmd.is_null() || cwd.is_null() || cols <= 0 || rows <= 0 || env_count < 0 { return ERROR; }
+
+        let (Some(keys), Some(vals)) = (unsafe { cstr_array(env_keys, env_count) }, unsafe { cstr_array(env_vals, env_count) })
+        else { return ERROR; };
+        let cmdline = unsafe { CStr::from_ptr(cmd) }.to_string_lossy();
+        let cwd     = unsafe { CStr::from_ptr(cwd) }.to_string_lossy();
+
+        let cmd = match Command::from_cmdline(&cmdline, &cwd) {
+            Ok(c)  => c.with_env(keys.into_iter().zip(vals).collect()),
+            Err(e) => { spawn_failed("", &e); return ERR_INVALID_CMDLINE; }
+        };
+        let size = PtySize { cols: cols as u16, rows: rows as u16, pixel_width: 0, pixel_height: 0 };
+        let opts = SpawnOptions { term: String::new(), ..SpawnOptions::default() };   // exactly the env given
+        match Pty::new(cmd, size, &opts) {
+            Ok(p)  => store(p),
+            Err(e) => spawn_failed("", &*e),
+        }
+    })
+}
+
+/// Like `bun_pty_spawn`, with extra settings given as a JSON `SpawnOptions` object
+/// (e.g. `{"env_blocklist": ["GITHUB_TOKEN", "AWS_*"]}`).
+///
+/// # Safety
+/// `cmd` and `cwd` must be valid NUL-terminated strings; `opts` must be null or one.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_spawn_with_options(
+    cmd:  *const c_char,
+    cwd:  *const c_char,
+    cols: c_int,
+    rows: c_int,
+    opts: *const c_char,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if cmd.is_null() || cwd.is_null() || cols <= 0 || rows <= 0 { return ERROR; }
+
+        let opts = match unsafe { parse_options(opts) } {
+            Ok(o)  => o,
+            Err(e) => { spawn_failed("", &e); return ERR_INVALID_JSON; }
+        };
+        let cmdline = unsafe { CStr::from_ptr(cmd) }.to_string_lossy();
+        let cwd     = unsafe { CStr::from_ptr(cwd) }.to_string_lossy();
+
+        let cmd = match Command::from_cmdline_with(&cmdline, &cwd, &opts) {
+            Ok(c)  => c.options_env(&opts),
+            Err(e) => { spawn_failed(&opts.label, &e); return ERR_INVALID_CMDLINE; }
+        };
+        let size = PtySize { cols: cols as u16, rows: rows as u16, pixel_width: 0, pixel_height: 0 };
+        match Pty::new(cmd, size, &opts) {
+            Ok(p)  => store(p),
+            Err(e) => spawn_failed(&opts.label, &*e),
+        }
+    })
+}
+
+/// Like `bun_pty_spawn`, but the `fd_count` descriptors in `fds` stay open in the child
+/// under the same numbers, e.g. a socket handed to a helper process. They must be open
+/// and above 2; stdin/stdout/stderr are always the PTY. Unix only: Windows fails the
+/// spawn unless `fd_count` is 0, as ConPTY offers no way to hand handles down.
+///
+/// # Safety
+/// `cmd` and `cwd` must be valid NUL-terminated strings; `fds` must point to `fd_count` ints.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_spawn_with_fds(
+    cmd:      *const c_char,
+    cwd:      *const c_char,
+    cols:     c_int,
+    rows:     c_int,
+    fds:      *const c_int,
+    fd_count: c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if cmd.is_null() || cwd.is_null() || cols <= 0 || rows <= 0 || fd_count < 0 { return ERROR; }
+        if fds.is_null() && fd_count > 0 { return ERROR; }
+
+        let keep_fds = match fd_count {
+            0 => Vec::new(),
+            n => unsafe { std::slice::from_raw_parts(fds, n as usize) }.to_vec(),
+        };
+        let cmdline = unsafe { CStr::from_ptr(cmd) }.to_string_lossy();
+        let cwd     = unsafe { CStr::from_ptr(cwd) }.to_string_lossy();
+
+        let cmd = match Command::from_cmdline(&cmdline, &cwd) {
+            Ok(c)  => c,
+            Err(e) => { spawn_failed("", &e); return ERR_INVALID_CMDLINE; }
+        };
+        let size = PtySize { cols: cols as u16, rows: rows as u16, pixel_width: 0, pixel_height: 0 };
+        let opts = SpawnOptions { keep_fds, ..SpawnOptions::default() };
+        match Pty::new(cmd, size, &opts) {
+            Ok(p)  => store(p),
+            Err(e) => spawn_failed("", &*e),
+        }
+    })
+}
+
+/// Spawns from a single JSON `SpawnSpec`. Returns `ERR_INVALID_JSON` if `spec` doesn't
+/// parse, or the usual spawn error codes if the spawn itself fails.
+///
+/// # Safety
+/// `spec` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_spawn_json(spec: *const c_char) -> c_int {
+    ffi_guard(ERROR, || {
+        if spec.is_null() { return ERROR; }
+
+        let spec: SpawnSpec = match serde_json::from_str(&unsafe { CStr::from_ptr(spec) }.to_string_lossy()) {
+            Ok(s)  => s,
+            Err(e) => { spawn_failed("", &e); return ERR_INVALID_JSON; }
+        };
+        if spec.cols == 0 || spec.rows == 0 {
+            return spawn_failed(&spec.options.label, &io::Error::new(io::ErrorKind::InvalidInput, "cols and rows must be at least 1"));
+        }
+
+        let size = PtySize { cols: spec.cols, rows: spec.rows, pixel_width: 0, pixel_height: 0 };
+        match Pty::new(spec.command(), size, &spec.options) {
+            Ok(p)  => store(p),
+            Err(e) => spawn_failed(&spec.options.label, &*e),
+        }
+    })
+}
+
+/// Name of a spawn error code, for `bun_pty_spawn_diagnostic`.
+fn error_kind(code: c_int) -> &'static str {
+    match code {
+        ERR_NOT_FOUND         => "not_found",
+        ERR_PERMISSION_DENIED => "permission_denied",
+        ERR_INVALID_JSON      => "invalid_json",
+        ERR_INVALID_CMDLINE   => "invalid_cmdline",
+        ERR_INVALID_CWD       => "invalid_cwd",
+        TOO_MANY_HANDLES      => "too_many_handles",
+        _                     => "other",
+    }
+}
+
+/// `bun_pty_spawn_json`, also writing a JSON report of the outcome to `out`:
+/// `{"ok": true, "handle": 3, "pid": 4242, "has_tty": true}` on success, or
+/// `{"ok": false, "code": -4, "error_kind": "not_found", "error_msg": "..."}`.
+/// Returns what `bun_pty_spawn_json` would. If the report doesn't fit in `out_len`
+/// bytes with its NUL, `out` is left empty; the spawn itself still happens.
+///
+/// # Safety
+/// `spec` must be a valid NUL-terminated string; `out` must point to `out_len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_spawn_diagnostic(spec: *const c_char, out: *mut c_char, out_len: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if out.is_null() || out_len <= 0 { return ERROR; }
+        LAST_ERROR.with(|l| l.borrow_mut().clear());
+        let handle = unsafe { bun_pty_spawn_json(spec) };
+        let report = match get(handle.max(0) as u32) {
+            Some(p) if handle > 0 => serde_json::json!({
+                "ok": true, "handle": handle, "pid": p.pid, "has_tty": p.has_tty, "exe": p.exe,
+            }),
+            _ => serde_json::json!({
+                "ok": false, "code": handle, "error_kind": error_kind(handle),
+                "error_msg": LAST_ERROR.with(|l| l.borrow().clone()),
+            }),
+        };
+        let report = report.to_string();
+        let n = if report.len() < out_len as usize { report.len() } else { 0 };
+        unsafe {
+            std::ptr::copy_nonoverlapping(report.as_ptr(), out as *mut u8, n);
+            *out.add(n) = 0;
+        }
+        handle
+    })
+}
+
+/// Like `bun_pty_spawn_with_options`, but registers the PTY as `desired_id` instead of
+/// the next free handle, for callers keeping their own handle namespace. Returns
+/// `desired_id` on success or `ERR_HANDLE_IN_USE` if a live handle already has it; the
+/// automatic handles skip IDs claimed this way.
+///
+/// # Safety
+/// `cmd` and `cwd` must be valid NUL-terminated strings; `opts` must be null or one.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_spawn_with_id(
+    desired_id: c_int,
+    cmd:        *const c_char,
+    cwd:        *const c_char,
+    cols:       c_int,
+    rows:       c_int,
+    opts:       *const c_char,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if desired_id <= 0 || cmd.is_null() || cwd.is_null() || cols <= 0 || rows <= 0 { return ERROR; }
+        if get(desired_id as u32).is_some() { return ERR_HANDLE_IN_USE; }   // don't spawn for nothing
+
+        let opts = match unsafe { parse_options(opts) } {
+            Ok(o)  => o,
+            Err(e) => { spawn_failed("", &e); return ERR_INVALID_JSON; }
+        };
+        let cmdline = unsafe { CStr::from_ptr(cmd) }.to_string_lossy();
+        let cwd     = unsafe { CStr::from_ptr(cwd) }.to_string_lossy();
+
+        let cmd = match Command::from_cmdline_with(&cmdline, &cwd, &opts) {
+            Ok(c)  => c.options_env(&opts),
+            Err(e) => { spawn_failed(&opts.label, &e); return ERR_INVALID_CMDLINE; }
+        };
+        let size = PtySize { cols: cols as u16, rows: rows as u16, pixel_width: 0, pixel_height: 0 };
+        match Pty::new(cmd, size, &opts) {
+            Ok(p)  => store_as(desired_id as u32, p),
+            Err(e) => spawn_failed(&opts.label, &*e),
+        }
+    })
+}
+
+/// Copies the calling thread's most recent spawn error message into `buf` (NUL-terminated,
+/// truncated to fit). Returns the full message length, so a result `>= len` means truncation;
+/// `0` if no error has been recorded.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_last_error(buf: *mut c_char, len: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if buf.is_null() || len <= 0 { return ERROR; }
+        LAST_ERROR.with(|l| unsafe { copy_c_str(l.borrow().as_bytes(), buf, len) })
+    })
+}
+
+/// Copies `s` into `buf` as a NUL-terminated string, truncated to fit, and returns the
+/// full length of `s`, so a return `>= len` means it was cut short.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes, `len > 0`.
+unsafe fn copy_c_str(s: &[u8], buf: *mut c_char, len: c_int) -> c_int {
+    let n = s.len().min(len as usize - 1);
+    unsafe {
+        std::ptr::copy_nonoverlapping(s.as_ptr(), buf as *mut u8, n);
+        *buf.add(n) = 0;
+    }
+    s.len() as c_int
+}
+
+/// # Safety
+/// `data` must point to at least `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_write(
+    handle: c_int,
+    data:   *const u8,
+    len:    c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || data.is_null() || len < 0 { return ERROR; }
+        with(handle as u32, |p| p.write(data, len as usize))
+    })
+}
+
+/// Like `bun_pty_write`, but the bytes jump the queue: they are written before anything
+/// queued and not yet sent, after at most one `WRITE_CHUNK` of a write already in
+/// progress. Bytes the PTY has already accepted still reach the child first, so if it
+/// has stopped reading entirely a priority write waits too; a Ctrl-C for a wedged
+/// child is better sent as a signal. Meant for control input during a large paste.
+///
+/// # Safety
+/// `data` must point to at least `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_write_priority(handle: c_int, data: *const u8, len: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || data.is_null() || len < 0 { return ERROR; }
+        let data = unsafe { std::slice::from_raw_parts(data, len as usize) }.to_vec();
+        with(handle as u32, |p| p.write_priority(data))
+    })
+}
+
+/// Queues the buffers of `iov` as one write, gathered in order, so a message built
+/// from several pieces reaches the child contiguously. Returns the total length
+/// queued; an empty list queues nothing and returns 0.
+///
+/// # Safety
+/// `iov` must point to `count` `IoVec`s, each `base` pointing to at least `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_writev(handle: c_int, iov: *const IoVec, count: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || count < 0 || (iov.is_null() && count > 0) { return ERROR; }
+        let iov = if count == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(iov, count as usize) } };
+        if iov.iter().any(|v| v.base.is_null() && v.len > 0) { return ERROR; }
+        let total: usize = iov.iter().map(|v| v.len).sum();
+        let Ok(total_c) = c_int::try_from(total) else { return ERROR; };
+        if total == 0 { return 0; }
+
+        let mut data = Vec::with_capacity(total);
+        for v in iov.iter().filter(|v| v.len > 0) {
+            data.extend_from_slice(unsafe { std::slice::from_raw_parts(v.base, v.len) });
+        }
+        with(handle as u32, |p| match p.enqueue(data) {
+            SUCCESS => total_c,
+            err     => err,
+        })
+    })
+}
+
+/// Like `bun_pty_write`, but blocks until the bytes have been written to the master and
+/// returns how many were, or `ERROR` / `CHILD_EXITED`. A child that stops reading its
+/// input keeps this blocked once the PTY's buffer is full.
+///
+/// # Safety
+/// `data` must point to at least `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_write_sync(
+    handle: c_int,
+    data:   *const u8,
+    len:    c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || data.is_null() || len < 0 { return ERROR; }
+        with(handle as u32, |p| p.write_sync(data, len as usize))
+    })
+}
+
+/// Blocks until every write made so far has reached the master and been flushed, e.g.
+/// before signalling the child. `timeout_ms` works as for `bun_pty_wait` (-1 waits
+/// forever); returns `SUCCESS`, `TIMEOUT`, or `ERROR` if a write failed.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_drain(handle: c_int, timeout_ms: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || timeout_ms < -1 { return ERROR; }
+        let timeout = (timeout_ms >= 0).then(|| Duration::from_millis(timeout_ms as u64));
+        with(handle as u32, |p| p.drain(timeout))
+    })
+}
+
+/// Sends a serial BREAK on the PTY (`tcdrain`, then `tcsendbreak`) after every write
+/// made so far, never in the middle of one. `duration_ms` 0 is the system default of
+/// 0.25–0.5 s; other values are rounded up to tenths of a second on Linux. The Linux
+/// PTY driver has no BREAK of its own, so a child only sees one where something
+/// emulating a serial line passes it on; the call still succeeds. `ERROR` on Windows
+/// and on pipes (`pipe_fallback`).
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_send_break(handle: c_int, duration_ms: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || duration_ms < 0 { return ERROR; }
+        with(handle as u32, |p| p.send_break(duration_ms))
+    })
+}
+
+/// `bun_pty_drain` without a timeout: once this returns `SUCCESS`, every write made
+/// before it, priority writes included, has been flushed to the master, in order.
+/// That says nothing about output; the child may not have read the input, let alone
+/// replied. Writes made from other threads meanwhile may land on either side.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_write_barrier(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.drain(None))
+    })
+}
+
+/// Writes the terminal's EOF character (VEOF from the PTY's termios, Ctrl-D if unset or
+/// on Windows). A canonical-mode reader sees end of input if nothing else is pending on
+/// the current line; otherwise it just gets that line without a newline.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_send_eof(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| {
+            let c = p.eof_char();
+            p.write(&c, 1)
+        })
+    })
+}
+
+/// Writes a NUL-terminated string; same return codes as `bun_pty_write`.
+///
+/// # Safety
+/// `s` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_write_str(handle: c_int, s: *const c_char) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || s.is_null() { return ERROR; }
+        let bytes = unsafe { CStr::from_ptr(s) }.to_bytes();
+        with(handle as u32, |p| p.write(bytes.as_ptr(), bytes.len()))
+    })
+}
+
+/// Copies up to `len` bytes of output into `buf`. The return value is one of:
+///
+/// - a positive count: that many bytes were copied;
+/// - `NO_DATA` (0): nothing is buffered yet, like `EAGAIN`; call again later;
+/// - `CHILD_EXITED`: the child is gone and all of its output has been read;
+/// - `EOF_REACHED`, only with `report_eof`: the output has closed but the child has
+///   not been reaped yet, returned in place of `NO_DATA` until `CHILD_EXITED` takes over;
+/// - `ERROR` or another negative code.
+///
+/// 0 never means end of output, as it does for `read(2)`. The other read calls follow
+/// the same contract. Also reads `bun_pty_subscribe` sub-handles, which never report
+/// `EOF_REACHED`.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_read(
+    handle: c_int,
+    buf:    *mut u8,
+    len:    c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
+        let got = |m| unsafe { deliver(m, buf) };
+        if let Some(sub) = get_sub(handle as u32) {
+            return got(sub.reader.read(len as usize)).unwrap_or(NO_DATA);
+        }
+        with_managed(handle as u32, |pty| match got(pty.read(len as usize).ok().flatten()) {
+            Some(n)                   => n,
+            None if pty.eof_pending() => EOF_REACHED,
+            None                      => NO_DATA,
+        })
+    })
+}
+
+/// Copies a read's data into `buf` and returns its length, or `CHILD_EXITED` for
+/// `End`; `None` when there was no data, which callers report their own way.
+///
+/// # Safety
+/// `buf` must have room for the data, i.e. the `max` it was read with.
+unsafe fn deliver(m: Option<Msg>, buf: *mut u8) -> Option<c_int> {
+    match m? {
+        Msg::Data(d, _) => {
+            unsafe { std::ptr::copy_nonoverlapping(d.as_ptr(), buf, d.len()); }
+            Some(d.len() as c_int)
+        }
+        Msg::End(_) => Some(CHILD_EXITED),
+    }
+}
+
+/// `bun_pty_read` with CSI, OSC and other escape sequences removed, e.g. for writing
+/// a log; text and control characters such as `\r` and `\n` pass through. A sequence
+/// split across reads is still removed whole. Keep to this call for a handle once
+/// started, since a sequence begun in a `bun_pty_read` chunk isn't recognised here.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_read_plain(handle: c_int, buf: *mut u8, len: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
+        let got = |m| unsafe { deliver(m, buf) };
+        if let Some(sub) = get_sub(handle as u32) {
+            return got(sub.reader.read_plain(len as usize)).unwrap_or(NO_DATA);
+        }
+        with_managed(handle as u32, |pty| match got(pty.read_plain(len as usize)) {
+            Some(n)                   => n,
+            None if pty.eof_pending() => EOF_REACHED,
+            None                      => NO_DATA,
+        })
+    })
+}
+
+/// Copies up to `len` bytes of buffered output into `buf` without consuming them:
+/// the next `bun_pty_read` returns the same bytes. Returns the count, 0 if nothing is
+/// buffered, or `CHILD_EXITED` once everything has been read. Never blocks.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_peek(handle: c_int, buf: *mut u8, len: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
+        let peek = |r: &Reader| {
+            let d = r.peek(len as usize);
+            if d.is_empty() && r.done.load(Ordering::Relaxed) { return CHILD_EXITED; }
+            unsafe { std::ptr::copy_nonoverlapping(d.as_ptr(), buf, d.len()); }
+            d.len() as c_int
+        };
+        if let Some(sub) = get_sub(handle as u32) { return peek(&sub.reader); }
+        with_managed(handle as u32, |pty| peek(&pty.reader))
+    })
+}
+
+/// `bun_pty_read`, with the output encoded as `ENCODING_RAW`, `ENCODING_BASE64` or
+/// `ENCODING_HEX` before it is copied. Only as many bytes are taken as fit in `len`
+/// once encoded, and each call's output stands alone (base64 is padded per call).
+/// Returns the encoded length written, or `BUFFER_TOO_SMALL` if `len` can't hold a
+/// single encoded byte (under 4 for base64, 2 for hex). Not NUL-terminated.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_read_encoded(handle: c_int, buf: *mut u8, len: c_int, encoding: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
+        let Some(max) = encodable(encoding, len as usize) else { return ERROR; };
+        if max == 0 { return BUFFER_TOO_SMALL; }
+        let got = |m| match m {
+            Some(Msg::Data(d, _)) => {
+                let e = encode(encoding, &d);
+                unsafe { std::ptr::copy_nonoverlapping(e.as_ptr(), buf, e.len()); }
+                Some(e.len() as c_int)
+            }
+            Some(Msg::End(_)) => Some(CHILD_EXITED),
+            None              => None,
+        };
+        if let Some(sub) = get_sub(handle as u32) {
+            return got(sub.reader.read(max)).unwrap_or(NO_DATA);
+        }
+        with_managed(handle as u32, |pty| match got(pty.read(max).ok().flatten()) {
+            Some(n)                   => n,
+            None if pty.eof_pending() => EOF_REACHED,
+            None                      => NO_DATA,
+        })
+    })
+}
+
+/// Like `bun_pty_read`, but when nothing is buffered waits up to `timeout_ms` for the
+/// next chunk instead of returning 0 at once, so a read loop can sleep here rather
+/// than poll. `-1` waits until data or exit, `0` behaves like `bun_pty_read`. Returns
+/// 0 on timeout (`EOF_REACHED` with `report_eof`, as for `bun_pty_read`).
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_read_timeout(handle: c_int, buf: *mut u8, len: c_int, timeout_ms: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 || timeout_ms < -1 { return ERROR; }
+        let timeout = (timeout_ms >= 0).then(|| Duration::from_millis(timeout_ms as u64));
+        let got = |m| unsafe { deliver(m, buf) };
+        if let Some(sub) = get_sub(handle as u32) {
+            return got(sub.reader.read_timeout(len as usize, timeout)).unwrap_or(NO_DATA);
+        }
+        with_managed(handle as u32, |pty| match got(pty.read_timeout(len as usize, timeout)) {
+            Some(n)                   => n,
+            None if pty.eof_pending() => EOF_REACHED,
+            None                      => NO_DATA,
+        })
+    })
+}
+
+/// Writes `input`, then waits up to `timeout_ms` for output as `bun_pty_read_timeout`
+/// does, for driving a REPL or a line protocol in one call. Returns the count copied
+/// into `out_buf`, 0 on timeout, or `CHILD_EXITED`. Best-effort: nothing marks where a
+/// response ends, so this returns what has arrived by the time the first chunk does,
+/// which may be part of the response (read on for the rest) or, if output was already
+/// buffered, something older; drain that first. With echo on, the input comes back too.
+///
+/// # Safety
+/// `input` must point to `input_len` readable bytes and `out_buf` to `out_len` writable ones.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_transact(
+    handle:     c_int,
+    input:      *const u8,
+    input_len:  c_int,
+    out_buf:    *mut u8,
+    out_len:    c_int,
+    timeout_ms: c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || input.is_null() || input_len < 0 || out_buf.is_null() || out_len <= 0 || timeout_ms < -1 {
+            return ERROR;
+        }
+        let timeout = (timeout_ms >= 0).then(|| Duration::from_millis(timeout_ms as u64));
+        with_managed(handle as u32, |pty| {
+            let written = pty.write(input, input_len as usize);
+            if written != SUCCESS { return written; }
+            match unsafe { deliver(pty.read_timeout(out_len as usize, timeout), out_buf) } {
+                Some(n)                   => n,
+                None if pty.eof_pending() => EOF_REACHED,
+                None                      => NO_DATA,
+            }
+        })
+    })
+}
+
+/// Like `bun_pty_read`, also storing in `*ts_out` when the oldest of the returned bytes
+/// came off the master, in nanoseconds since spawn. Needs `"timestamps": true` at spawn;
+/// without it `*ts_out` is always 0.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes; `ts_out` must be a valid, writable pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_read_timed(
+    handle: c_int,
+    buf:    *mut u8,
+    len:    c_int,
+    ts_out: *mut u64,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 || ts_out.is_null() { return ERROR; }
+        let got = |m| match m {
+            Some(Msg::Data(d, ts)) => {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(d.as_ptr(), buf, d.len());
+                    *ts_out = ts;
+                }
+                Some(d.len() as c_int)
+            }
+            Some(Msg::End(_)) => Some(CHILD_EXITED),
+            None              => None,
+        };
+        if let Some(sub) = get_sub(handle as u32) {
+            return got(sub.reader.read(len as usize)).unwrap_or(NO_DATA);
+        }
+        with_managed(handle as u32, |pty| match got(pty.read(len as usize).ok().flatten()) {
+            Some(n)                   => n,
+            None if pty.eof_pending() => EOF_REACHED,
+            None                      => NO_DATA,
+        })
+    })
+}
+
+/// Copies the next complete output line into `buf`, NUL-terminated and ending in
+/// `\n` (`\r\n` is folded); returns its length, 0 if no full line has arrived yet,
+/// or `CHILD_EXITED` once everything has been read. Lines that do not fit, and an
+/// unterminated last line, come back without the `\n`; long ones in several pieces,
+/// never splitting a UTF-8 character, so `len` must leave room for at least one
+/// character plus the NUL. Don't mix with the other read calls on the same handle:
+/// bytes held here for an unfinished line are not seen by them.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_read_line(
+    handle: c_int,
+    buf:    *mut c_char,
+    len:    c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len < 5 { return ERROR; }
+        let got = |m| match m {
+            Some(Msg::Data(d, _)) => {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(d.as_ptr(), buf as *mut u8, d.len());
+                    *buf.add(d.len()) = 0;
+                }
+                d.len() as c_int
+            }
+            Some(Msg::End(_)) => CHILD_EXITED,
+            None              => NO_DATA,
+        };
+        if let Some(sub) = get_sub(handle as u32) { return got(sub.reader.read_line(len as usize - 1)); }
+        with_managed(handle as u32, |pty| match pty.read_line(len as usize - 1) {
+            Ok(m)  => got(m),
+            Err(_) => ERROR,
+        })
+    })
+}
+
+/// Reads the master directly, for handles spawned with `"manage_reads": false`
+/// (`ERROR` otherwise). Blocks until output arrives unless the master fd has been
+/// made non-blocking, in which case 0 means nothing is ready; pair it with polling
+/// `bun_pty_get_master_fd`. Returns `CHILD_EXITED` once the slave side is closed.
+/// Logging and the on-data callback don't apply to data read this way.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_raw_read(
+    handle: c_int,
+    buf:    *mut u8,
+    len:    c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
+        let buf = unsafe { std::slice::from_raw_parts_mut(buf, len as usize) };
+        with(handle as u32, |pty| pty.raw_read(buf))
+    })
+}
+
+/// Reads the child's stderr, for handles spawned with `"separate_stderr": true`. Returns
+/// the byte count, 0 if nothing is buffered, `CHILD_EXITED` once the pipe is drained and
+/// closed, or `ERROR` if the handle's stderr goes to the PTY as usual.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_read_stderr(
+    handle: c_int,
+    buf:    *mut u8,
+    len:    c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
+        with(handle as u32, |pty| match pty.read_stderr(len as usize) {
+            Some(m) => unsafe { deliver(m, buf) }.unwrap_or(NO_DATA),
+            None    => ERROR,
+        })
+    })
+}
+
+/// Like `bun_pty_read`, but hands back the whole available chunk in a fresh buffer so
+/// nothing is truncated. On `SUCCESS`, `*out_ptr`/`*out_len` hold the data (null/0 if
+/// nothing is buffered); release it with `bun_pty_free`.
+///
+/// # Safety
+/// `out_ptr` and `out_len` must be valid, writable pointers.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_read_alloc(
+    handle:  c_int,
+    out_ptr: *mut *mut u8,
+    out_len: *mut usize,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || out_ptr.is_null() || out_len.is_null() { return ERROR; }
+        unsafe {
+            *out_ptr = std::ptr::null_mut();
+            *out_len = 0;
+        }
+        let got = |m| match m {
+            Some(Msg::Data(d, _)) => {
+                let len = d.len();
+                unsafe {
+                    *out_ptr = Box::into_raw(d.into_boxed_slice()) as *mut u8;
+                    *out_len = len;
+                }
+                SUCCESS
+            }
+            Some(Msg::End(_)) => CHILD_EXITED,
+            None              => SUCCESS,              // no data
+        };
+        if let Some(sub) = get_sub(handle as u32) { return got(sub.reader.read(usize::MAX)); }
+        with_managed(handle as u32, |pty| match pty.read(usize::MAX) {
+            Ok(m)  => got(m),
+            Err(_) => ERROR,
+        })
+    })
+}
+
+/// A zeroed buffer of `len` bytes for `bun_pty_write_owned` to hand back; null if
+/// `len` is 0. Release one that isn't written with `bun_pty_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_alloc(len: usize) -> *mut u8 {
+    ffi_guard(std::ptr::null_mut(), || {
+        if len == 0 { return std::ptr::null_mut(); }
+        Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+    })
+}
+
+/// Queues a buffer from `bun_pty_alloc` as `bun_pty_write` would, but without copying
+/// it: the write-thread writes from it directly. Ownership passes to the library in
+/// every case, so the buffer must not be used or freed afterwards, even on an error.
+///
+/// # Safety
+/// `ptr`/`len` must come from a single `bun_pty_alloc` call, with all `len` bytes to be written.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_write_owned(handle: c_int, ptr: *mut u8, len: usize) -> c_int {
+    ffi_guard(ERROR, || {
+        if ptr.is_null() || len == 0 { return ERROR; }
+        let data = unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)) }.into_vec();
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.enqueue(data))
+    })
+}
+
+/// Releases a buffer returned by `bun_pty_read_alloc` or `bun_pty_alloc`.
+///
+/// # Safety
+/// `ptr`/`len` must come from a single `bun_pty_read_alloc` or `bun_pty_alloc` call and
+/// not be freed twice.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_free(ptr: *mut u8, len: usize) {
+    ffi_guard((), || {
+        if ptr.is_null() { return; }
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)) });
+    })
+}
+
+/// Same as `bun_pty_read`, which no longer sleeps out a drain window on the calling
+/// thread either; kept so existing callers keep working.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_try_read(
+    handle: c_int,
     buf:    *mut u8,
     len:    c_int,
 ) -> c_int {
-    if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
-    with(handle as u32, |pty| match pty.read() {
-        Ok(Msg::Data(d)) if !d.is_empty() => {
-            let n = d.len().min(len as usize);
-            unsafe { std::ptr::copy_nonoverlapping(d.as_ptr(), buf, n); }
-            n as c_int
-        }
-        Ok(Msg::End) => CHILD_EXITED,
-        _            => 0,                         // no data
-    })
+    unsafe { bun_pty_read(handle, buf, len) }
 }
 
+/// Sets the size in character cells, keeping the pixel size last set with
+/// `bun_pty_resize_px` (0, unknown, if there was none).
 #[unsafe(no_mangle)]
 pub extern "C" fn bun_pty_resize(handle: c_int, cols: c_int, rows: c_int) -> c_int {
-    if handle <= 0 || cols <= 0 || rows <= 0 { return ERROR; }
-    with(handle as u32, |p| {
-        p.resize(PtySize { cols: cols as u16, rows: rows as u16, pixel_width: 0, pixel_height: 0 })
+    ffi_guard(ERROR, || {
+        if handle <= 0 || cols <= 0 || rows <= 0 { return ERROR; }
+        with(handle as u32, |p| p.resize(p.cells(cols as u16, rows as u16)))
+    })
+}
+
+/// `bun_pty_resize` with the window's size in pixels as well, which the child reads
+/// back as `ws_xpixel` / `ws_ypixel` from `TIOCGWINSZ`; image protocols such as sixel
+/// or kitty graphics use it to work out the cell size. 0 leaves a dimension unknown.
+/// ConPTY has no pixel size, so on Windows only `cols` and `rows` take effect.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_resize_px(handle: c_int, cols: c_int, rows: c_int, px_width: c_int, px_height: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || cols <= 0 || rows <= 0 { return ERROR; }
+        let (Ok(pixel_width), Ok(pixel_height)) = (u16::try_from(px_width), u16::try_from(px_height)) else { return ERROR; };
+        with(handle as u32, |p| p.resize(PtySize { cols: cols as u16, rows: rows as u16, pixel_width, pixel_height }))
+    })
+}
+
+/// Like `bun_pty_resize`, but only the last of a burst of calls is applied, `delay_ms`
+/// after it was made. A plain `bun_pty_resize` cancels a pending one.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_resize_debounced(handle: c_int, cols: c_int, rows: c_int, delay_ms: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || cols <= 0 || rows <= 0 || delay_ms < 0 { return ERROR; }
+        with(handle as u32, |p| p.resize_debounced(p.cells(cols as u16, rows as u16), Duration::from_millis(delay_ms as u64)))
+    })
+}
+
+/// `bun_pty_resize_debounced` with the pixel size of `bun_pty_resize_px`, applied
+/// together with the cell size once the burst is over.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_resize_debounced_px(
+    handle:    c_int,
+    cols:      c_int,
+    rows:      c_int,
+    px_width:  c_int,
+    px_height: c_int,
+    delay_ms:  c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || cols <= 0 || rows <= 0 || delay_ms < 0 { return ERROR; }
+        let (Ok(pixel_width), Ok(pixel_height)) = (u16::try_from(px_width), u16::try_from(px_height)) else { return ERROR; };
+        let size = PtySize { cols: cols as u16, rows: rows as u16, pixel_width, pixel_height };
+        with(handle as u32, |p| p.resize_debounced(size, Duration::from_millis(delay_ms as u64)))
+    })
+}
+
+/// Switches the PTY between raw (`raw != 0`: no line editing, no echo) and cooked mode,
+/// e.g. to hide password input. Unix only; always `ERROR` on Windows.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_raw_mode(handle: c_int, raw: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.set_raw_mode(raw != 0))
+    })
+}
+
+/// Writes the PTY's current size into `cols_out` / `rows_out`; `bun_pty_get_size_px`
+/// has the pixel size too.
+///
+/// # Safety
+/// `cols_out` and `rows_out` must be valid, writable pointers.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_get_size(handle: c_int, cols_out: *mut c_int, rows_out: *mut c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || cols_out.is_null() || rows_out.is_null() { return ERROR; }
+        with(handle as u32, |p| {
+            let size = p.get_size();
+            unsafe {
+                *cols_out = size.cols as c_int;
+                *rows_out = size.rows as c_int;
+            }
+            SUCCESS
+        })
+    })
+}
+
+/// Like `bun_pty_get_size`, also writing the pixel size (0 if never set) into
+/// `px_width_out` / `px_height_out`.
+///
+/// # Safety
+/// All four out-pointers must be valid and writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_get_size_px(
+    handle:        c_int,
+    cols_out:      *mut c_int,
+    rows_out:      *mut c_int,
+    px_width_out:  *mut c_int,
+    px_height_out: *mut c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || cols_out.is_null() || rows_out.is_null() || px_width_out.is_null() || px_height_out.is_null() {
+            return ERROR;
+        }
+        with(handle as u32, |p| {
+            let size = p.get_size();
+            unsafe {
+                *cols_out = size.cols as c_int;
+                *rows_out = size.rows as c_int;
+                *px_width_out = size.pixel_width as c_int;
+                *px_height_out = size.pixel_height as c_int;
+            }
+            SUCCESS
+        })
     })
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn bun_pty_kill(handle: c_int) -> c_int {
-    if handle <= 0 { return ERROR; }
-    with(handle as u32, |p| p.kill())
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.kill())
+    })
+}
+
+/// Sends `signum` (e.g. `SIGINT`, `SIGTERM`, `SIGHUP`, `SIGWINCH`) to the child.
+/// Returns `CHILD_EXITED` if the child is already gone, `PID_UNAVAILABLE` if its PID
+/// isn't known.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_signal(handle: c_int, signum: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || signum <= 0 { return ERROR; }
+        with(handle as u32, |p| p.signal(signum))
+    })
+}
+
+/// Stops the child politely: sends `SIGTERM` (Ctrl-C on Windows), waits up to
+/// `grace_ms` for it to exit, and only then kills it. Returns `TERMINATED` (0) if it
+/// exited within the grace period, `FORCE_KILLED` (1) if it had to be killed, or
+/// `CHILD_EXITED` if it was already reaped. After `FORCE_KILLED` the exit status
+/// arrives once the wait-thread reaps it; `bun_pty_wait` for it.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_terminate(handle: c_int, grace_ms: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || grace_ms < 0 { return ERROR; }
+        with(handle as u32, |p| p.terminate(Duration::from_millis(grace_ms as u64)))
+    })
+}
+
+/// Sends `signum` to the child's whole process group (`kill(-pgid, signum)`), reaching
+/// anything it started in that group too. Windows signals only the child.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_signal_group(handle: c_int, signum: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || signum <= 0 { return ERROR; }
+        with(handle as u32, |p| p.signal_group(signum))
+    })
+}
+
+/// The child's process group ID (`getpgid`); `CHILD_EXITED` once it is gone,
+/// `PID_UNAVAILABLE` without a PID, `ERROR` on Windows.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_get_pgid(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.pgid())
+    })
+}
+
+/// The terminal's foreground process group ID (`tcgetpgrp`), for telling which job
+/// has the terminal: it equals `bun_pty_get_pgid` while the shell sits at its prompt
+/// and changes while a command runs. `CHILD_EXITED` once the child is gone; `ERROR`
+/// on Windows, on pipes (`pipe_fallback`) and without `controlling_tty`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_get_foreground_pgid(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.foreground_pgid())
+    })
 }
 
+/// The child's PID; `ERROR` for an unknown handle, `PID_UNAVAILABLE` if the platform
+/// didn't report one for the child. Calls that act on the PID (`bun_pty_signal`,
+/// `bun_pty_get_pgid`, `bun_pty_get_rusage`…) return `PID_UNAVAILABLE` then too.
 #[unsafe(no_mangle)]
 pub extern "C" fn bun_pty_get_pid(handle: c_int) -> c_int {
-    if handle <= 0 { return ERROR; }
-    with(handle as u32, |p| p.pid)
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.pid.map_or(PID_UNAVAILABLE, |pid| pid as c_int))
+    })
+}
+
+/// Untruncated child PID; -1 for unknown handles, `PID_UNAVAILABLE` when the PID isn't
+/// available.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_get_pid64(handle: c_int) -> i64 {
+    ffi_guard(-1, || {
+        if handle <= 0 { return -1; }
+        get(handle as u32).map_or(-1, |p| p.pid.map_or(PID_UNAVAILABLE.into(), i64::from))
+    })
+}
+
+/// Milliseconds since output last came off the PTY (since the spawn if none has yet),
+/// whether or not anyone has read it; -1 for unknown handles. Cheap enough to poll
+/// for an "idle" indicator.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_last_activity_ms(handle: c_int) -> i64 {
+    ffi_guard(-1, || {
+        if handle <= 0 { return -1; }
+        get(handle as u32).map_or(-1, |p| {
+            let now = p.spawned.elapsed().as_millis() as u64;
+            now.saturating_sub(p.last_output.load(Ordering::Relaxed)) as i64
+        })
+    })
+}
+
+/// Raw master fd, for polling the PTY from an external event loop; `ERROR` on Windows.
+/// The fd stays owned by the handle and is closed by `bun_pty_close`. The internal
+/// read-thread drains the same fd, so reading it directly races with `bun_pty_read`;
+/// spawn with `"manage_reads": false` to read it yourself.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_get_master_fd(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.master_fd())
+    })
 }
 
+/// The child's exit code, or `IDLE_KILLED` if `bun_pty_set_idle_timeout` killed it.
 #[unsafe(no_mangle)]
 pub extern "C" fn bun_pty_get_exit_code(handle: c_int) -> c_int {
-    if handle <= 0 { return ERROR; }
-    with(handle as u32, |p| p.exit_code.load(Ordering::Relaxed))
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| match p.idle_killed.load(Ordering::Relaxed) {
+            true  => IDLE_KILLED,
+            false => p.exit_code.load(Ordering::Relaxed),
+        })
+    })
+}
+
+/// Stores the exit code as the unsigned 32-bit value the OS reported. On Windows a
+/// crash code such as 0xC0000005 comes back intact, where `bun_pty_get_exit_code`
+/// shows it as a negative number. `ERROR` until the child has been reaped.
+///
+/// # Safety
+/// `out` must be a valid, writable pointer to a `u32`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_get_exit_code_u32(handle: c_int, out: *mut u32) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || out.is_null() { return ERROR; }
+        with(handle as u32, |p| {
+            if !*p.reaped.0.locked() { return ERROR; }
+            unsafe { out.write(p.exit_code.load(Ordering::Relaxed) as u32); }
+            SUCCESS
+        })
+    })
+}
+
+/// Kills the child after `ms` milliseconds without output, counted from this call and
+/// reset by every chunk read; 0 turns it off. `bun_pty_get_exit_code` then reports
+/// `IDLE_KILLED` instead of the exit status.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_idle_timeout(handle: c_int, ms: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || ms < 0 { return ERROR; }
+        with(handle as u32, |p| p.set_idle_timeout(Duration::from_millis(ms as u64)))
+    })
+}
+
+/// Replays a recorded script of input, e.g. to drive a child the same way in every test
+/// run. `script_json` is an array of `{"delay_ms": 100, "bytes": "ls\n"}` entries; each is
+/// queued as `bun_pty_write` would, `delay_ms` after the one before it (after this call
+/// for the first). Control characters go in as JSON escapes such as `"\u0003"`; only
+/// text can be sent this way. Returns at once, before anything is written; starting a
+/// script stops the one already playing. `ERR_INVALID_JSON` if the script doesn't parse.
+///
+/// # Safety
+/// `script_json` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_play_script(handle: c_int, script_json: *const c_char) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || script_json.is_null() { return ERROR; }
+        let steps = match serde_json::from_str(&unsafe { CStr::from_ptr(script_json) }.to_string_lossy()) {
+            Ok(s)  => s,
+            Err(_) => return ERR_INVALID_JSON,
+        };
+        with(handle as u32, |p| p.play_script(steps))
+    })
+}
+
+/// Stops a `bun_pty_play_script` playback; entries already queued are still written.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_stop_script(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| { p.stop_script(); SUCCESS })
+    })
+}
+
+/// Caps how fast output is read off the master at `bytes_per_sec`, averaged over
+/// about 100 ms; 0 removes the cap. Reading slower doesn't lose anything: once the
+/// kernel buffer fills, the child blocks on its writes until it drains.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_read_rate_limit(handle: c_int, bytes_per_sec: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || bytes_per_sec < 0 { return ERROR; }
+        with(handle as u32, |p| p.set_read_rate_limit(bytes_per_sec as u64))
+    })
+}
+
+/// Number of output bytes buffered and ready for `bun_pty_read`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_bytes_available(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        if let Some(sub) = get_sub(handle as u32) { return sub.reader.available(); }
+        with(handle as u32, |p| p.reader.available())
+    })
+}
+
+/// Fills `*out` with the handle's I/O totals since spawn: bytes read off the master
+/// (including any the caller hasn't read yet), bytes written to it, and master reads.
+///
+/// # Safety
+/// `out` must be a valid, writable pointer to a `PtyStats`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_get_stats(handle: c_int, out: *mut PtyStats) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || out.is_null() { return ERROR; }
+        with(handle as u32, |p| {
+            unsafe { out.write(p.get_stats()); }
+            SUCCESS
+        })
+    })
+}
+
+/// Fills `*out` with the child's resident memory and CPU time (the child process itself,
+/// not its descendants). Supported on Linux and macOS; elsewhere, for example on
+/// Windows, it returns `ERROR`. `CHILD_EXITED` once the child is gone, `PID_UNAVAILABLE`
+/// if its PID isn't known.
+///
+/// # Safety
+/// `out` must be a valid, writable pointer to a `PtyRusage`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_get_rusage(handle: c_int, out: *mut PtyRusage) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || out.is_null() { return ERROR; }
+        with(handle as u32, |p| match p.rusage() {
+            Ok(u)  => { unsafe { out.write(u); } SUCCESS }
+            Err(e) => e,
+        })
+    })
+}
+
+/// Copies the path of the executable that was spawned into `buf`, NUL-terminated, and
+/// returns its length (truncated if `>= len`). On Unix a bare name is the absolute
+/// path found on `PATH`; a relative `cwd` leaves it relative. On Windows it is `cmd`
+/// as given, since the lookup happens inside `CreateProcess`.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_get_exe_path(handle: c_int, buf: *mut c_char, len: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
+        with(handle as u32, |p| unsafe { copy_c_str(p.exe.as_bytes(), buf, len) })
+    })
+}
+
+/// Copies the path of the PTY's slave device (`ptsname`, e.g. `/dev/pts/4`) into `buf`,
+/// NUL-terminated, and returns its length (truncated if `>= len`): the TTY column `ps`
+/// and `w` show for the child. `ERROR` on Windows and on pipes (`pipe_fallback`).
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_get_tty_name(handle: c_int, buf: *mut c_char, len: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
+        with(handle as u32, |p| match p.tty_path() {
+            Some(path) => unsafe { copy_c_str(path.to_string_lossy().as_bytes(), buf, len) },
+            None       => ERROR,
+        })
+    })
+}
+
+/// Copies the `label` the PTY was spawned with into `buf`, NUL-terminated, and returns
+/// its length (truncated if `>= len`); 0 if it has none.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_get_label(handle: c_int, buf: *mut c_char, len: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len <= 0 { return ERROR; }
+        with(handle as u32, |p| unsafe { copy_c_str(p.config.1.label.as_bytes(), buf, len) })
+    })
+}
+
+/// 1 if the child runs on a real PTY, 0 if `pipe_fallback` spawned it on pipes; then
+/// it isn't a tty to the child, resizes reach nobody, and there is no master fd.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_has_tty(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.has_tty as c_int)
+    })
+}
+
+/// Copies the newest output kept by `scrollback_bytes` into `buf`, oldest byte first,
+/// and returns how many bytes were copied; if `len` is smaller than what's kept, the
+/// most recent `len` bytes. Unaffected by reads, so a consumer attaching late can
+/// replay what it missed. 0 when scrollback is off. The first byte may fall in the
+/// middle of a UTF-8 character or escape sequence.
+///
+/// # Safety
+/// `buf` must point to `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_get_scrollback(handle: c_int, buf: *mut u8, len: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || buf.is_null() || len < 0 { return ERROR; }
+        let buf = unsafe { std::slice::from_raw_parts_mut(buf, len as usize) };
+        with(handle as u32, |p| p.scrollback(buf) as c_int)
+    })
 }
 
+/// Liveness check that leaves buffered output alone: 1 while running, 0 once exited.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_is_alive(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| if p.exited.load(Ordering::Relaxed) { 0 } else { 1 })
+    })
+}
+
+/// Writes the `probe_len` bytes at `probe` to the child every `interval_ms` through the
+/// normal write queue, and marks the handle stale (see `bun_pty_is_stale`) once the child
+/// turns out to be gone. `interval_ms == 0` turns it off; `probe` may then be null.
+///
+/// # Safety
+/// `probe` must point to at least `probe_len` readable bytes when `interval_ms > 0`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_set_keepalive(
+    handle:      c_int,
+    interval_ms: c_int,
+    probe:       *const u8,
+    probe_len:   c_int,
+) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || interval_ms < 0 || probe_len < 0 { return ERROR; }
+        if interval_ms > 0 && probe.is_null() && probe_len > 0 { return ERROR; }
+        let probe = match (interval_ms, probe_len) {
+            (0, _) | (_, 0) => Vec::new(),
+            _               => unsafe { std::slice::from_raw_parts(probe, probe_len as usize) }.to_vec(),
+        };
+        let interval = Duration::from_millis(interval_ms as u64);
+        with(handle as u32, |p| p.set_keepalive(interval, probe))
+    })
+}
+
+/// 1 once a keepalive probe found the child gone, else 0.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_is_stale(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.stale.load(Ordering::Relaxed) as c_int)
+    })
+}
+
+/// Signal number that terminated the child, or 0 if it exited normally (or hasn't yet).
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_get_exit_signal(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.exit_signal.load(Ordering::Relaxed))
+    })
+}
+
+/// Pushes output to `cb` as it arrives instead of queueing it for `bun_pty_read`.
+///
+/// `cb` runs on the PTY's read-thread, not the caller's thread, so it must be safe to
+/// call from there (in Bun, a `threadsafe` `JSCallback`). `data` is only valid for the
+/// duration of the call. Exit is still reported through `bun_pty_read`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_on_data(handle: c_int, cb: DataCallback, user: *mut c_void) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| {
+            *p.on_data.locked() = Some(OnData { cb, handle, user });
+            SUCCESS
+        })
+    })
+}
+
+/// Calls `cb` once with the exit code and signal (0 for a normal exit) when the child
+/// has been reaped; both are already stored, so `bun_pty_get_exit_code` agrees inside
+/// `cb`. It normally runs on the wait-thread, so the same threading rules as for
+/// `bun_pty_set_on_data` apply; if the child has already been reaped it runs right
+/// away on the caller's thread. Setting it again before exit replaces the callback;
+/// after a callback has fired, nothing else is called for this handle.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_on_exit(handle: c_int, cb: ExitCallback, user: *mut c_void) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| {
+            p.on_exit.locked().0 = Some(OnExit { cb, handle, user });
+            // the wait-thread flags the reap before it looks for a callback, so one of us fires it
+            if *p.reaped.0.locked() { p.fire_on_exit(); }
+            SUCCESS
+        })
+    })
+}
+
+/// Reverts to polling mode. A callback already in flight may still complete.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_clear_on_data(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| { p.on_data.locked().take(); SUCCESS })
+    })
+}
+
+/// Number of writes queued and not yet handed to the child. The one being written
+/// right now doesn't count, so 0 doesn't mean everything is flushed; see `bun_pty_drain`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_write_queue_len(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.queue_len() as c_int)
+    })
+}
+
+/// Calls `cb` with the queue length whenever a write takes the queue (as counted by
+/// `bun_pty_write_queue_len`) from below `mark` to `mark` or more, so the caller can
+/// stop feeding input until it goes down again. `cb` runs on the thread that made the
+/// write, inside the write call.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_on_high_water(handle: c_int, mark: c_int, cb: HighWaterCallback, user: *mut c_void) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || mark <= 0 { return ERROR; }
+        with(handle as u32, |p| {
+            *p.high_water.locked() = Some(OnHighWater { cb, handle, user, mark: mark as usize });
+            SUCCESS
+        })
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_clear_on_high_water(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| { p.high_water.locked().take(); SUCCESS })
+    })
+}
+
+/// Calls `cb` with the new size each time a resize has been applied to the PTY, i.e.
+/// the kernel accepted it; a resize that fails doesn't call it. It runs on the thread
+/// that applied the size: the caller's for `bun_pty_resize`, the timer thread for
+/// `bun_pty_resize_debounced`. Whether the child reacts to the change is up to it.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_on_resize(handle: c_int, cb: ResizeCallback, user: *mut c_void) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| { *p.on_resize.locked() = Some(OnResize { cb, handle, user }); SUCCESS })
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_clear_on_resize(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| { p.on_resize.locked().take(); SUCCESS })
+    })
+}
+
+/// Calls `cb` with the new group each time the terminal's foreground process group
+/// (`bun_pty_get_foreground_pgid`) changes, e.g. when the shell starts a command and
+/// again when it gets the terminal back. The group is polled every 250 ms, so a
+/// command that finishes faster than that may go unseen. `cb` runs on a helper thread,
+/// which ends when the handle is closed or the child exits. `ERROR` on Windows.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_on_fg_change(handle: c_int, cb: FgChangeCallback, user: *mut c_void) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.set_on_fg_change(Some(OnFgChange { cb, handle, user })))
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_clear_on_fg_change(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| p.set_on_fg_change(None))
+    })
+}
+
+/// Stops pulling output from the PTY. Nothing is lost: the kernel buffer fills up and
+/// eventually blocks the child's writes, much like XOFF.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_pause(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| { p.set_paused(true); SUCCESS })
+    })
+}
+
+/// Resumes output delivery after `bun_pty_pause`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_resume(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| { p.set_paused(false); SUCCESS })
+    })
+}
+
+/// Tees every chunk of raw child output into the file at `path` (truncated first).
+///
+/// # Safety
+/// `path` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_enable_logging(handle: c_int, path: *const c_char) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || path.is_null() { return ERROR; }
+        let path = unsafe { CStr::from_ptr(path) }.to_string_lossy();
+        with(handle as u32, |p| p.enable_logging(&path))
+    })
+}
+
+/// Stops teeing output; the session itself keeps running.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_disable_logging(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        with(handle as u32, |p| { p.disable_logging(); SUCCESS })
+    })
+}
+
+/// Blocks until the child exits and returns its exit code, or `TIMEOUT` if
+/// `timeout_ms` elapses first. `-1` waits forever, `0` only polls.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_wait(handle: c_int, timeout_ms: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 || timeout_ms < -1 { return ERROR; }
+        let Some(pty) = get(handle as u32) else { return ERROR; };
+        let timeout = (timeout_ms >= 0).then(|| Duration::from_millis(timeout_ms as u64));
+        pty.wait(timeout)
+    })
+}
+
+/// Respawns an exited child with the command, environment, cwd and spawn options it was
+/// first started with, at the PTY's current size, under the same handle. Output not yet
+/// read from the old child is dropped, as is anything set up by later calls (on-data
+/// callback, logging, keepalive, pause). `ERROR` while the child is still running;
+/// other failures return the usual spawn error codes.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_restart(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        let Some(old) = get(handle as u32) else { return ERROR; };
+        if !*old.reaped.0.locked() { return ERROR; }
+        let new = match old.respawn() {
+            Ok(p)  => p,
+            Err(e) => return spawn_failed(&old.config.1.label, &*e),
+        };
+        match replace(handle as u32, new) {
+            Ok(old)  => { old.shutdown(); SUCCESS }
+            Err(new) => { new.shutdown(); ERROR }     // closed while we were spawning
+        }
+    })
+}
+
+/// Returns a sub-handle that receives its own copy of all output from now on (recent
+/// output can be fetched with `bun_pty_get_scrollback`), read with `bun_pty_read` or
+/// any of the other channel reads (`peek`, `read_line`, `read_encoded`…) independently
+/// of the handle and any other subscriber; it sees the exit too.
+/// `bun_pty_close` on it detaches just that subscriber. Its queue is unbounded, so a
+/// subscriber that stops reading should be closed. Takes a handle, not a sub-handle.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_subscribe(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return ERROR; }
+        match get(handle as u32) {
+            Some(p) if p.raw_reader.is_none() => add_sub(p).map_or(ERROR, |id| id as c_int),
+            Some(_)                           => MANUAL_READS,
+            None                              => ERROR,
+        }
+    })
+}
+
+/// Kills the child and releases the handle; its callbacks are dropped, so none fires
+/// after this returns, bar one already under way. A `detached` child is left running: we
+/// stop watching it and drop our end of the PTY, and since nothing here reaps it after
+/// that it stays a zombie once it exits, until the host process itself exits.
 #[unsafe(no_mangle)]
 pub extern "C" fn bun_pty_close(handle: c_int) {
-    if handle <= 0 { return; }
-    REG.lock().unwrap().remove(&(handle as u32));
+    ffi_guard((), || {
+        if handle <= 0 || remove_sub(handle as u32) { return; }
+        if let Some(p) = remove(handle as u32) { p.shutdown(); }
+    })
+}
+
+/// Readies the library: sets up the handle and sub-handle registries, and on Unix the
+/// `THREADING_SHARED` write queue and poll-loop wake pipe, then allows spawning again
+/// after `bun_pty_shutdown`. No threads are started here; the shared loop and write
+/// pool start with the first PTY that needs them and stop once idle. Calling it is
+/// optional on first load, and it's a no-op while already initialised.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_init() -> c_int {
+    ffi_guard(ERROR, || {
+        lazy_static::initialize(&REG);
+        lazy_static::initialize(&CLOSED);
+        lazy_static::initialize(&SUBS);
+        #[cfg(unix)]
+        if shared::init().is_err() { return ERROR; }
+        SHUT_DOWN.store(false, Ordering::Relaxed);
+        SUCCESS
+    })
+}
+
+/// Chooses how PTYs spawned from now on are driven; those already running keep theirs.
+/// `THREADING_DEDICATED` (the default) gives each PTY its own read-, write- and
+/// wait-thread. `THREADING_SHARED` reads and reaps every PTY from a single poll loop and
+/// writes from a pool of up to four threads, for hosts running hundreds of PTYs. On-data
+/// callbacks then all run on the loop's thread, so a slow one holds up every PTY. PTYs
+/// on pipes (`pipe_fallback`) and `separate_stderr`'s stderr still get their own
+/// threads. Their masters are made non-blocking, so `bun_pty_raw_read` on one returns
+/// 0 when nothing is ready. `THREADING_SHARED` is Unix only; elsewhere it returns `ERROR`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_threading_mode(mode: c_int) -> c_int {
+    ffi_guard(ERROR, || match mode {
+        #[cfg(unix)]
+        THREADING_DEDICATED | THREADING_SHARED => { shared::set_mode(mode); SUCCESS }
+        #[cfg(not(unix))]
+        THREADING_DEDICATED => SUCCESS,
+        _ => ERROR,
+    })
+}
+
+/// Tears the library down before it is unloaded: refuses new spawns, closes every
+/// handle, waits up to `timeout_ms` (-1 = forever) for all background threads to
+/// finish, and resets handle numbering and limits so `bun_pty_init` starts afresh.
+/// Once it returns `SUCCESS` every thread of ours has been joined and no callback will
+/// fire, so the library can be `dlclose`d. `TIMEOUT` if some thread is still stuck,
+/// e.g. reading a PTY a grandchild still holds open.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_shutdown(timeout_ms: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        {
+            let _reg = REG.locked();                  // `store` checks it under this lock
+            SHUT_DOWN.store(true, Ordering::Relaxed);
+        }
+        for p in remove_all() { p.shutdown(); }
+        CLOSED.locked().clear();
+        NEXT.store(1, Ordering::Relaxed);
+        MAX_HANDLES.store(0, Ordering::Relaxed);
+
+        let deadline = (timeout_ms >= 0).then(|| Instant::now() + Duration::from_millis(timeout_ms as u64));
+        let (lock, cv) = &WORKERS;
+        let mut running = lock.locked();
+        while *running > 0 {
+            running = match deadline {
+                None    => cv.wait(running).unwrap_or_else(PoisonError::into_inner),
+                Some(d) => {
+                    let left = d.saturating_duration_since(Instant::now());
+                    if left.is_zero() { return TIMEOUT; }
+                    cv.wait_timeout(running, left).unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+        drop(running);
+        // the count drops as each closure returns, just before its thread ends
+        for h in std::mem::take(&mut *JOINS.locked()) { let _ = h.join(); }
+        SUCCESS
+    })
+}
+
+/// Closes every open handle as `bun_pty_close` would, e.g. at process shutdown.
+/// Returns how many were closed.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_close_all() -> c_int {
+    ffi_guard(ERROR, || {
+        let all = remove_all();
+        for p in &all { p.shutdown(); }
+        all.len() as c_int
+    })
+}
+
+/// Caps how many handles may be open at once; spawns past the cap fail with
+/// `TOO_MANY_HANDLES` before anything is started. 0 removes the cap (the default).
+/// Lowering it below the current count closes nothing, it only refuses new spawns.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_set_max_handles(limit: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if limit < 0 { return ERROR; }
+        MAX_HANDLES.store(limit as usize, Ordering::Relaxed);
+        SUCCESS
+    })
+}
+
+/// Number of open handles, exited or not.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_count() -> c_int {
+    ffi_guard(ERROR, || {
+        REG.locked().len() as c_int
+    })
+}
+
+/// Fills `out` with up to `max` open handles, lowest first, and returns how many are
+/// open; more than `max` means the list was cut short. `out` may be null when `max` is 0,
+/// to just get the count. Sub-handles from `bun_pty_subscribe` aren't included.
+///
+/// # Safety
+/// `out` must point to at least `max` writable `c_int`s.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bun_pty_list_handles(out: *mut c_int, max: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if max < 0 || (out.is_null() && max > 0) { return ERROR; }
+        let mut ids: Vec<u32> = REG.locked().keys().copied().collect();
+        ids.sort_unstable();
+        for (i, id) in ids.iter().take(max as usize).enumerate() {
+            unsafe { *out.add(i) = *id as c_int; }
+        }
+        ids.len() as c_int
+    })
+}
+
+/// `HANDLE_ALIVE`, `HANDLE_EXITED` (child gone, handle still open), `HANDLE_CLOSED`
+/// (closed recently enough to still be remembered) or `HANDLE_UNKNOWN`. A sub-handle
+/// from `bun_pty_subscribe` reports the state of its PTY until it is closed itself.
+#[unsafe(no_mangle)]
+pub extern "C" fn bun_pty_handle_state(handle: c_int) -> c_int {
+    ffi_guard(ERROR, || {
+        if handle <= 0 { return HANDLE_UNKNOWN; }
+        let id = handle as u32;
+        let sub = || SUBS.locked().get(&id).map(|(p, _)| p.clone());
+        match get(id).or_else(sub) {
+            Some(p) if p.exited.load(Ordering::Relaxed)  => HANDLE_EXITED,
+            Some(_)                                      => HANDLE_ALIVE,
+            None if CLOSED.locked().contains(&id)        => HANDLE_CLOSED,
+            None                                         => HANDLE_UNKNOWN,
+        }
+    })
 }
\ No newline at end of file
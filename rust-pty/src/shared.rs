@@ -0,0 +1,398 @@
+//! shared.rs  —  `THREADING_SHARED`: one poll loop for every PTY's reads and reaps,
+//! and a small pool for the writes, instead of three threads per PTY. The masters are
+//! non-blocking, so a child that stops reading its input parks its writes with the
+//! loop rather than tying up a pool thread.
+
+use super::*;
+use std::os::unix::io::RawFd;
+
+static MODE: AtomicI32 = AtomicI32::new(THREADING_DEDICATED);
+
+const TICK: Duration = Duration::from_millis(100);       // how often exits are checked for
+const EXITING: Duration = Duration::from_millis(10);     // …once the output has ended
+const POOL_SIZE: usize = 4;
+const POOL_IDLE: Duration = Duration::from_secs(1);      // an idle pool thread exits after this
+
+pub(crate) fn set_mode(mode: c_int) {
+    MODE.store(mode, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled() -> bool {
+    MODE.load(Ordering::Relaxed) == THREADING_SHARED
+}
+
+/* ---------- poll loop ---------- */
+
+/// A PTY the loop reads and reaps for, in place of its read- and wait-thread.
+struct Polled {
+    pty:  Arc<Pty>,
+    fd:   RawFd,
+    rdr:  Option<Box<dyn Read + Send>>,                  // None once output has ended, or never managed
+    pid:  u32,
+    _child: Box<dyn Child + Send + Sync>,                // reaped through `pid`, kept as the wait-thread would
+    tx:   Sender<Msg>,
+    buf_size: usize,
+    quiet_until: Instant,
+    reaped_at: Option<Instant>,
+    last_read: Instant,
+}
+
+struct Loop {
+    pending: Vec<Polled>,                                // handed over, not yet picked up by the loop
+    parked:  Vec<Arc<Pty>>,                              // writes the master had no room for, ditto
+    running: bool,
+    wake:    Option<(RawFd, RawFd)>,                     // self-pipe, kept for the life of the process
+}
+
+static LOOP: Mutex<Loop> = Mutex::new(Loop { pending: Vec::new(), parked: Vec::new(), running: false, wake: None });
+
+/// Hands a freshly spawned PTY's output and child over to the loop, starting it if needed.
+pub(crate) fn adopt(
+    pty: &Arc<Pty>,
+    fd: RawFd,
+    rdr: Option<Box<dyn Read + Send>>,
+    (child, pid): (Box<dyn Child + Send + Sync>, u32),
+    tx: Sender<Msg>,
+    buf_size: usize,
+    quiet_until: Instant,
+) -> io::Result<()> {
+    set_nonblocking(fd)?;                                // the reader and writer share its flags
+    let mut l = LOOP.locked();
+    if l.wake.is_none() { l.wake = Some(self_pipe()?); }
+    let now = Instant::now();
+    l.pending.push(Polled { pty: pty.clone(), fd, rdr, pid, _child: child, tx, buf_size, quiet_until, reaped_at: None, last_read: now });
+    start(&mut l);
+    drop(l);
+    wake();
+    Ok(())
+}
+
+/// Leaves `pty`'s writes to the loop until its master has room again; it stays
+/// scheduled meanwhile, so new writes just queue up behind.
+fn park(pty: Arc<Pty>) {
+    let mut l = LOOP.locked();
+    l.parked.push(pty);
+    start(&mut l);
+    drop(l);
+    wake();
+}
+
+fn start(l: &mut Loop) {
+    if !l.running {
+        l.running = true;
+        spawn_worker(run);
+    }
+}
+
+/// Sets up the write pool's queue and the loop's wake pipe ahead of the first shared
+/// PTY. The threads still start on demand and go again once idle.
+pub(crate) fn init() -> io::Result<()> {
+    lazy_static::initialize(&JOBS);
+    let mut l = LOOP.locked();
+    if l.wake.is_none() { l.wake = Some(self_pipe()?); }
+    Ok(())
+}
+
+/// Gets the loop to look at its PTYs again, e.g. after a resume or a close.
+pub(crate) fn wake() {
+    let Some((_, w)) = LOOP.locked().wake else { return };
+    unsafe { libc::write(w, [1u8].as_ptr().cast(), 1); }   // a full pipe already means "wake up"
+}
+
+fn self_pipe() -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 { return Err(io::Error::last_os_error()); }
+    for fd in fds {
+        set_nonblocking(fd)?;
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC); }
+    }
+    Ok((fds[0], fds[1]))
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Non-blocking `wait_child`: `None` while the child is still running.
+fn poll_child(pid: u32) -> Option<(i32, i32)> {
+    let mut status = 0;
+    match unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) } {
+        0 => None,
+        -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => None,
+        -1 => Some((-1, 0)),                                 // reaped elsewhere
+        _ if libc::WIFSIGNALED(status) => Some((1, libc::WTERMSIG(status))),
+        _ => Some((libc::WEXITSTATUS(status), 0)),
+    }
+}
+
+impl Polled {
+    /// Has room to take what the master has: not paused, closing, held back by the
+    /// read rate limit, or held up by a full `read_channel_capacity` channel (retried
+    /// on the next tick).
+    fn wants_read(&self) -> bool {
+        self.rdr.is_some()
+            && !*self.pty.paused.0.locked()
+            && !self.pty.closing.load(Ordering::Relaxed)
+            && !self.tx.is_full()
+            && self.pty.read_delay().is_zero()
+    }
+
+    /// One read, now that `poll` says it won't block.
+    fn read(&mut self, buf: &mut [u8]) {
+        let Some(rdr) = &mut self.rdr else { return };
+        match rdr.read(&mut buf[..self.buf_size]) {
+            Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock) => {}
+            Ok(0) | Err(_) => { self.stop_reading(); self.pty.output_ended(&self.tx); }
+            Ok(n) => {
+                self.last_read = Instant::now();
+                if !self.pty.take_chunk(&self.tx, &buf[..n], self.quiet_until) { self.stop_reading(); }
+            }
+        }
+    }
+
+    fn stop_reading(&mut self) {
+        self.rdr = None;
+        if let Some(f) = self.pty.log.locked().as_mut() { let _ = f.flush(); }
+    }
+
+    /// Reaps the child if it has exited and sends `End` when the wait-thread would;
+    /// false once there is nothing left to do for this PTY.
+    fn step(&mut self) -> bool {
+        let pty = self.pty.clone();
+        let closing = pty.closing.load(Ordering::Relaxed);
+        if closing && self.rdr.is_some() { self.stop_reading(); }
+        if closing && pty.detached { return false; }          // the child is on its own now
+        let Some(reaped_at) = self.reaped_at.or_else(|| {
+            let (code, signal) = poll_child(self.pid)?;
+            pty.set_reaped(code, signal);
+            Some(Instant::now())
+        }) else { return true };
+        self.reaped_at = Some(reaped_at);
+        // as on the wait-thread: `End` after the last output, or once the master has
+        // been quiet for `linger` while we are polling it
+        let quiet = self.last_read.max(reaped_at).elapsed() >= pty.linger && self.wants_read();
+        if self.rdr.is_none() || closing || quiet { pty.finish(&self.tx); }
+        self.rdr.is_some()
+    }
+
+    /// How long `poll` may wait before `step` has something to do for this PTY.
+    fn due_in(&self) -> Duration {
+        let due = match self.reaped_at {
+            None if self.rdr.is_none() => EXITING,
+            None                       => TICK,
+            Some(_) if self.pty.end_sent.load(Ordering::SeqCst) => TICK,
+            Some(r) => (self.last_read.max(r) + self.pty.linger).saturating_duration_since(Instant::now()),
+        };
+        match self.pty.read_delay() {
+            d if self.rdr.is_some() && !d.is_zero() => due.min(d),
+            _ => due,
+        }
+    }
+}
+
+fn run() {
+    let mut polled: Vec<Polled> = Vec::new();
+    let mut parked: Vec<Arc<Pty>> = Vec::new();
+    let mut buf = vec![0; READ_BUFFER_RANGE.1];
+    loop {
+        let wake_fd = {
+            let mut l = LOOP.locked();
+            polled.append(&mut l.pending);
+            parked.append(&mut l.parked);
+            if polled.is_empty() && parked.is_empty() { l.running = false; return; }
+            l.wake.map_or(-1, |w| w.0)
+        };
+        // nobody is waiting on a closed PTY's writes; `shutdown` has dropped them
+        parked.retain(|p| !p.closing.load(Ordering::Relaxed));
+
+        let mut fds = vec![libc::pollfd { fd: wake_fd, events: libc::POLLIN, revents: 0 }];
+        let mut owners = Vec::new();
+        let mut timeout = TICK;
+        for (i, p) in polled.iter().enumerate() {
+            if p.wants_read() {
+                fds.push(libc::pollfd { fd: p.fd, events: libc::POLLIN, revents: 0 });
+                owners.push(i);
+            }
+            timeout = timeout.min(p.due_in());
+        }
+        let reads = fds.len();
+        fds.extend(parked.iter().map(|p| libc::pollfd { fd: p.master_fd(), events: libc::POLLOUT, revents: 0 }));
+        let n = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout.as_millis() as c_int) };
+
+        if n > 0 {
+            if fds[0].revents != 0 {
+                let mut sink = [0u8; 64];
+                while unsafe { libc::read(wake_fd, sink.as_mut_ptr().cast(), sink.len()) } > 0 {}
+            }
+            for (f, &i) in fds[1..reads].iter().zip(&owners) {
+                if f.revents != 0 { polled[i].read(&mut buf); }
+            }
+            // room again, or an error the next write will run into
+            let mut ready = fds[reads..].iter().map(|f| f.revents != 0);
+            for p in parked.extract_if(.., |_| ready.next().unwrap_or(false)) { schedule(p); }
+        }
+        polled.retain_mut(Polled::step);
+    }
+}
+
+/* ---------- write pool ---------- */
+
+/// A PTY's write side while the pool drives it, in place of its write-thread, with
+/// whatever the master didn't have room for yet.
+pub(crate) struct Writer {
+    wtr:  Box<dyn Write + Send>,
+    rx_p: Receiver<Vec<u8>>,
+    rx_w: Receiver<WriteMsg>,
+    coalesce: bool,
+    urgent: Option<(Vec<u8>, usize)>,                    // a priority write, and how much of it is out
+    msg:    Option<Outgoing>,                            // the queued message under way
+}
+
+/// A queued message part-way out; dropped unfinished, it reports what got out.
+struct Outgoing {
+    data: Vec<u8>,
+    done: usize,
+    acks: Vec<Ack>,
+}
+
+impl Drop for Outgoing {
+    fn drop(&mut self) {
+        settle(std::mem::take(&mut self.acks), self.done, self.done == self.data.len());
+    }
+}
+
+enum Sent {
+    All,
+    Blocked,                                             // the master is full; try again once it isn't
+    Failed,
+}
+
+impl Writer {
+    pub(crate) fn new(wtr: Box<dyn Write + Send>, rx_p: Receiver<Vec<u8>>, rx_w: Receiver<WriteMsg>, coalesce: bool) -> Self {
+        Self { wtr, rx_p, rx_w, coalesce, urgent: None, msg: None }
+    }
+
+    /// Writes what is queued, as the write-thread would: a queued message goes out
+    /// `WRITE_CHUNK` bytes at a time with priority writes sent before each chunk and
+    /// before it is acknowledged. Stops at the first write the master would block on,
+    /// keeping the rest for the next call.
+    fn pump(&mut self, pty: &Pty) -> Sent {
+        loop {
+            if self.urgent.is_none() { self.urgent = self.rx_p.try_recv().ok().map(|d| (d, 0)); }
+            if let Some((d, done)) = &mut self.urgent {
+                match push(pty, &mut self.wtr, d, done) {
+                    Sent::All => self.urgent = None,
+                    stuck     => return stuck,
+                }
+                continue;
+            }
+            let m = match &mut self.msg {
+                Some(m) => m,
+                None => {
+                    let Ok(m) = self.rx_w.try_recv() else { return Sent::All };
+                    let (data, acks) = gather(m, &self.rx_w, self.coalesce);
+                    self.msg.insert(Outgoing { data, done: 0, acks })
+                }
+            };
+            if m.done == m.data.len() {
+                self.msg = None;                         // acknowledged as it goes
+                continue;
+            }
+            let end = (m.done + WRITE_CHUNK).min(m.data.len());
+            if let stuck @ (Sent::Blocked | Sent::Failed) = push(pty, &mut self.wtr, &m.data[..end], &mut m.done) {
+                return stuck;
+            }
+        }
+    }
+}
+
+/// Runs `f` while no queued message or priority write is part-way out on `pty`, and
+/// none can start.
+pub(crate) fn between_messages<T>(pty: &Pty, f: impl FnOnce() -> T) -> T {
+    loop {
+        let slot = pty.writer.locked();
+        if slot.as_ref().is_none_or(|w| w.msg.is_none() && w.urgent.is_none()) { return f(); }
+        drop(slot);
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Writes `data` from `*done` on, as far as the master takes it without blocking.
+fn push(pty: &Pty, wtr: &mut Box<dyn Write + Send>, data: &[u8], done: &mut usize) -> Sent {
+    while *done < data.len() {
+        match wtr.write(&data[*done..]) {
+            Ok(0) => return Sent::Failed,
+            Ok(n) => {
+                *done += n;
+                pty.stats.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock  => return Sent::Blocked,
+            Err(_) => return Sent::Failed,
+        }
+    }
+    if wtr.flush().is_err() { return Sent::Failed; }
+    Sent::All
+}
+
+lazy_static::lazy_static! {
+    static ref JOBS: (Sender<Arc<Pty>>, Receiver<Arc<Pty>>) = unbounded();
+}
+static POOL_THREADS: Mutex<usize> = Mutex::new(0);
+
+/// Has a pool thread write out what `pty` has queued. The caller makes sure a PTY is
+/// scheduled at most once at a time, so its writes keep their order.
+pub(crate) fn schedule(pty: Arc<Pty>) {
+    let _ = JOBS.0.send(pty);
+    let mut n = POOL_THREADS.locked();
+    if *n < POOL_SIZE {
+        *n += 1;
+        spawn_worker(work);
+    }
+}
+
+/// Exits after `POOL_IDLE` without a job, or at the next tick once `bun_pty_shutdown`
+/// is waiting for it.
+fn work() {
+    let mut since = Instant::now();
+    loop {
+        match JOBS.1.recv_timeout(TICK) {
+            Ok(pty) => { drain(&pty); since = Instant::now(); }
+            Err(_) if since.elapsed() < POOL_IDLE && !SHUT_DOWN.load(Ordering::Relaxed) => {}
+            Err(_) => {
+                let mut n = POOL_THREADS.locked();
+                if JOBS.1.is_empty() { *n -= 1; return; }
+            }
+        }
+    }
+}
+
+/// Writes until `pty`'s queues are empty, or parks it with the loop once the master
+/// is full. A failed write drops the receivers, so further writes fail as they do once
+/// a write-thread has gone.
+fn drain(pty: &Arc<Pty>) {
+    loop {
+        {
+            let mut slot = pty.writer.locked();
+            if let Some(w) = slot.as_mut() {
+                let closing = pty.closing.load(Ordering::Relaxed);
+                match w.pump(pty) {
+                    Sent::Blocked if !closing => {
+                        drop(slot);
+                        park(pty.clone());
+                        return;
+                    }
+                    Sent::All if !closing => {}
+                    _ => *slot = None,
+                }
+            }
+        }
+        pty.write_scheduled.store(false, Ordering::SeqCst);
+        // a write may have come in after the queues looked empty but before the flag dropped
+        if pty.queue_len() == 0 || pty.write_scheduled.swap(true, Ordering::SeqCst) { return; }
+    }
+}
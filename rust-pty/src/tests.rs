@@ -0,0 +1,564 @@
+//! tests.rs  —  unit tests for the reader, and the FFI driven against real children
+
+use super::*;
+
+#[test]
+fn read_line_cuts_binary_without_a_utf8_boundary() {
+    let (tx, rx) = unbounded();
+    let rdr = Reader::new(rx, true);
+    tx.send(Msg::Data(vec![0x80; 10], 0)).unwrap();
+    tx.send(Msg::End(0)).unwrap();
+
+    let mut got = Vec::new();
+    while let Some(Msg::Data(d, _)) = rdr.read_line(4) {
+        assert!(!d.is_empty() && d.len() <= 4);
+        got.extend(d);
+    }
+    assert_eq!(got, vec![0x80; 10]);
+}
+
+#[test]
+fn peek_then_read_returns_the_same_bytes() {
+    let (tx, rx) = unbounded();
+    let rdr = Reader::new(rx, false);
+    tx.send(Msg::Data(b"hello ".to_vec(), 0)).unwrap();
+    tx.send(Msg::Data(b"world".to_vec(), 0)).unwrap();
+
+    let peeked = rdr.peek(8);
+    assert_eq!(peeked, b"hello wo");
+    assert_eq!(rdr.peek(8), peeked);                 // still there
+    let Some(Msg::Data(d, _)) = rdr.read(8) else { panic!("nothing to read") };
+    assert_eq!(d, peeked);
+}
+
+#[test]
+fn utf8_cuts_land_on_character_boundaries() {
+    for s in ["é", "€", "😀"] {
+        let b = format!("a{s}b");
+        let b = b.as_bytes();
+        let end = 1 + s.len();                      // just past the character
+        for cut in 1..end {
+            assert_eq!(utf8_tail(&b[..cut]), 1, "{s} cut at {cut}");
+            assert_eq!(utf8_floor(b, cut), 1, "{s} floored at {cut}");
+        }
+        assert_eq!(utf8_tail(&b[..end]), end);
+        assert_eq!(utf8_floor(b, end), end);
+    }
+    // stray continuation bytes and invalid leads count as complete characters
+    for bad in [[b'a', 0x80], [b'a', 0xF8], [b'a', 0xFF]] {
+        assert_eq!(utf8_tail(&bad), 2);
+    }
+    assert_eq!(utf8_floor(&[0xFF, b'a'], 1), 1);
+    assert_eq!(utf8_tail(&[]), 0);
+    assert_eq!(utf8_floor(&[], 3), 0);
+}
+
+#[test]
+fn encodings_round_trip_their_sizes() {
+    let cases: &[(c_int, &[u8], &[u8])] = &[
+        (ENCODING_RAW,    b"",        b""),
+        (ENCODING_RAW,    b"\x00ab",  b"\x00ab"),
+        (ENCODING_BASE64, b"",        b""),
+        (ENCODING_BASE64, b"f",       b"Zg=="),
+        (ENCODING_BASE64, b"fo",      b"Zm8="),
+        (ENCODING_BASE64, b"foo",     b"Zm9v"),
+        (ENCODING_BASE64, b"\xff\xfe", b"//4="),
+        (ENCODING_HEX,    b"",        b""),
+        (ENCODING_HEX,    b"\x00\xab", b"00ab"),
+    ];
+    for &(enc, data, want) in cases {
+        assert_eq!(encode(enc, data), want, "encoding {enc} of {data:?}");
+        assert!(encodable(enc, want.len()).unwrap() >= data.len());
+    }
+    assert_eq!(encodable(ENCODING_RAW, 10), Some(10));
+    assert_eq!(encodable(ENCODING_BASE64, 10), Some(6));
+    assert_eq!(encodable(ENCODING_BASE64, 3), Some(0));
+    assert_eq!(encodable(ENCODING_HEX, 9), Some(4));
+    assert_eq!(encodable(ENCODING_HEX, 0), Some(0));
+    assert_eq!(encodable(3, 10), None);
+    assert_eq!(encodable(-1, 10), None);
+}
+
+#[test]
+fn stripper_drops_escape_sequences() {
+    let strip = |input: &[u8]| ansi::Stripper::default().strip(input);
+    assert_eq!(strip(b"a\x1b[1;31mred\x1b[0m b"), b"ared b");                  // CSI
+    assert_eq!(strip(b"\x1b]0;title\x07text"), b"text");                       // OSC, BEL
+    assert_eq!(strip(b"\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\"), b"link");  // OSC, ST
+    assert_eq!(strip(b"\x1b(Bx\x1b=y\x1b7z"), b"xyz");                         // bare ESC
+    assert_eq!(strip(b"tab\there\r\n"), b"tab\there\r\n");
+
+    let mut s = ansi::Stripper::default();
+    let mut out = s.strip(b"one\x1b[3");
+    out.extend(s.strip(b"8;5;1mtwo\x1b]2;ti"));
+    out.extend(s.strip(b"tle\x1b"));
+    out.extend(s.strip(b"\\three"));
+    assert_eq!(out, b"onetwothree");
+}
+
+/// Tests that spawn or touch the globals (`MAX_HANDLES`, the threading mode) take this.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn spawn(cmdline: &str) -> c_int {
+    let cmd = std::ffi::CString::new(cmdline).unwrap();
+    unsafe { bun_pty_spawn(cmd.as_ptr(), c"/tmp".as_ptr(), 80, 24) }
+}
+
+/// Number of `spawn_worker` threads still running.
+fn workers() -> usize {
+    *WORKERS.0.locked()
+}
+
+/// Waits up to `timeout` for the worker count to drop to `n`.
+fn workers_down_to(n: usize, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while workers() > n {
+        if Instant::now() >= deadline { return false; }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+#[test]
+fn close_stops_the_threads_of_a_running_child() {
+    let _serial = SERIAL.locked();
+    let before = workers();
+    let h = spawn("sleep 100");
+    assert!(h > 0);
+    bun_pty_close(h);                               // its read-, write- and wait-thread should go with it
+    assert!(workers_down_to(before, Duration::from_secs(2)));
+}
+
+#[test]
+fn max_handles_holds_under_concurrent_spawns() {
+    let _serial = SERIAL.locked();
+    bun_pty_set_max_handles(bun_pty_count() + 2);
+
+    let spawned: Vec<c_int> = (0..8)
+        .map(|_| thread::spawn(|| spawn("sleep 5")))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|t| t.join().unwrap())
+        .collect();
+    bun_pty_set_max_handles(0);
+
+    let ok: Vec<c_int> = spawned.iter().copied().filter(|&h| h > 0).collect();
+    for &h in &ok { bun_pty_close(h); }
+    assert_eq!(ok.len(), 2);
+    assert!(spawned.iter().all(|&h| h > 0 || h == TOO_MANY_HANDLES));
+}
+
+extern "C" fn count_exit(_h: c_int, _code: i32, _sig: i32, user: *mut c_void) {
+    unsafe { &*(user as *const AtomicUsize) }.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test]
+fn close_drops_the_exit_callback() {
+    let _serial = SERIAL.locked();
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    let h = spawn("sleep 100");
+    assert!(h > 0);
+    assert_eq!(bun_pty_set_on_exit(h, count_exit, &FIRED as *const _ as *mut c_void), SUCCESS);
+    bun_pty_close(h);
+    thread::sleep(Duration::from_millis(300));  // the kill is reaped well within this
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn debounced_resize_keeps_the_pixel_size() {
+    let _serial = SERIAL.locked();
+    let h = spawn("sleep 100");
+    assert_eq!(bun_pty_resize_debounced_px(h, 100, 30, 1000, 600, 10), SUCCESS);
+    thread::sleep(Duration::from_millis(200));
+
+    let mut size = [0; 4];
+    let [c, r, w, ht] = size.each_mut().map(|v| v as *mut c_int);
+    assert_eq!(unsafe { bun_pty_get_size_px(h, c, r, w, ht) }, SUCCESS);
+    bun_pty_close(h);
+    assert_eq!(size, [100, 30, 1000, 600]);
+}
+
+#[test]
+fn resize_keeps_the_pixel_size() {
+    let _serial = SERIAL.locked();
+    let h = spawn("sleep 100");
+    assert_eq!(bun_pty_resize_px(h, 100, 30, 1000, 600), SUCCESS);
+    assert_eq!(bun_pty_resize(h, 120, 40), SUCCESS);
+
+    let mut size = [0; 4];
+    let [c, r, w, ht] = size.each_mut().map(|v| v as *mut c_int);
+    assert_eq!(unsafe { bun_pty_get_size_px(h, c, r, w, ht) }, SUCCESS);
+    bun_pty_close(h);
+    assert_eq!(size, [120, 40, 1000, 600]);
+}
+
+/// Reads until `CHILD_EXITED`, until `done` says the output so far is enough, or until
+/// `timeout` has passed.
+fn read_for(h: c_int, timeout: Duration, done: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    let deadline = Instant::now() + timeout;
+    let (mut out, mut buf) = (Vec::new(), [0u8; 4096]);
+    while Instant::now() < deadline && !done(&out) {
+        match unsafe { bun_pty_read(h, buf.as_mut_ptr(), buf.len() as c_int) } {
+            n if n > 0 => out.extend_from_slice(&buf[..n as usize]),
+            NO_DATA    => thread::sleep(Duration::from_millis(5)),
+            _          => break,
+        }
+    }
+    out
+}
+
+fn read_all(h: c_int, timeout: Duration) -> Vec<u8> {
+    read_for(h, timeout, |_| false)
+}
+
+/// Whether the output comes to contain `needle` within `timeout`.
+fn read_until(h: c_int, needle: &str, timeout: Duration) -> bool {
+    let seen = |out: &[u8]| String::from_utf8_lossy(out).contains(needle);
+    seen(&read_for(h, timeout, seen))
+}
+
+#[test]
+fn json_env_reaches_the_child_in_order() {
+    let _serial = SERIAL.locked();
+    let spec = cr#"{"cmd": "/usr/bin/env", "env": {"B": "2", "A": "1", "A": "3"}, "term": ""}"#;
+    let h = unsafe { bun_pty_spawn_json(spec.as_ptr()) };
+    assert!(h > 0);
+    let out = read_all(h, Duration::from_secs(5));
+    bun_pty_close(h);
+    let out = String::from_utf8_lossy(&out);
+    assert_eq!(out.lines().collect::<Vec<_>>(), ["B=2", "A=1", "A=3"]);
+}
+
+#[test]
+fn a_closed_handle_reports_closed() {
+    let _serial = SERIAL.locked();
+    let h = spawn("sleep 100");
+    assert_eq!(bun_pty_handle_state(h), HANDLE_ALIVE);
+    bun_pty_close(h);
+    assert_eq!(bun_pty_handle_state(h), HANDLE_CLOSED);
+    assert_eq!(bun_pty_handle_state(i32::MAX), HANDLE_UNKNOWN);
+}
+
+#[test]
+fn spawn_with_id_refuses_a_taken_id() {
+    let _serial = SERIAL.locked();
+    let spawn_as = |id: c_int| unsafe { bun_pty_spawn_with_id(id, c"sleep 100".as_ptr(), c"/tmp".as_ptr(), 80, 24, std::ptr::null()) };
+    let id = 1_000_000;
+    assert_eq!(spawn_as(id), id);
+    assert_eq!(spawn_as(id), ERR_HANDLE_IN_USE);    // live
+
+    let sub = bun_pty_subscribe(id);
+    assert!(sub > 0);
+    assert_eq!(spawn_as(sub), ERR_HANDLE_IN_USE);   // held by a sub-handle
+    bun_pty_close(sub);
+    bun_pty_close(id);
+    assert_eq!(spawn_as(id), id);                   // free again once closed
+    bun_pty_close(id);
+}
+
+#[test]
+fn subscribers_each_get_all_the_output() {
+    let _serial = SERIAL.locked();
+    let h = spawn("sh -c 'sleep 0.3; echo fanned out; sleep 0.3; echo and again'");
+    assert!(h > 0);
+    let (a, b) = (bun_pty_subscribe(h), bun_pty_subscribe(h));
+    assert!(a > 0 && b > 0 && a != b);
+
+    assert!(read_until(a, "fanned out", Duration::from_secs(3)));
+    assert!(read_until(b, "fanned out", Duration::from_secs(3)));
+    bun_pty_close(a);                               // unsubscribes just `a`
+    assert_eq!(bun_pty_handle_state(a), HANDLE_CLOSED);
+    let mut buf = [0u8; 16];
+    assert_eq!(unsafe { bun_pty_read(a, buf.as_mut_ptr(), buf.len() as c_int) }, ERROR);
+
+    assert!(read_until(b, "and again", Duration::from_secs(3)));
+    assert!(read_until(h, "and again", Duration::from_secs(3)));
+    bun_pty_close(b);
+    bun_pty_close(h);
+}
+
+#[test]
+fn close_stdin_spawn_does_not_wait_for_the_child_to_read() {
+    let _serial = SERIAL.locked();
+    let input = "x".repeat(99) + "\n";
+    let opts = serde_json::json!({ "initial_input": input.repeat(700), "close_stdin": true }).to_string();
+    let opts = std::ffi::CString::new(opts).unwrap();
+
+    let start = Instant::now();
+    let h = unsafe { bun_pty_spawn_with_options(c"sh -c 'sleep 1; wc -c'".as_ptr(), c"/tmp".as_ptr(), 80, 24, opts.as_ptr()) };
+    assert!(h > 0);
+    assert!(start.elapsed() < Duration::from_millis(500));   // 70 KB is far more than the tty buffers
+
+    let out = read_all(h, Duration::from_secs(10));
+    bun_pty_close(h);
+    assert!(String::from_utf8_lossy(&out).trim_end().ends_with("70000"));
+}
+
+/// `THREADING_SHARED` for PTYs spawned until this is dropped.
+struct SharedMode;
+impl SharedMode {
+    fn on() -> Self {
+        assert_eq!(bun_pty_set_threading_mode(THREADING_SHARED), SUCCESS);
+        SharedMode
+    }
+}
+impl Drop for SharedMode {
+    fn drop(&mut self) { bun_pty_set_threading_mode(THREADING_DEDICATED); }
+}
+
+#[test]
+fn shared_mode_writes_the_keepalive_probe() {
+    let _serial = SERIAL.locked();
+    let _shared = SharedMode::on();
+    let h = spawn("cat");
+    let probe = b"ping\n";
+    assert_eq!(unsafe { bun_pty_set_keepalive(h, 50, probe.as_ptr(), probe.len() as c_int) }, SUCCESS);
+    let seen = read_until(h, "ping", Duration::from_secs(2));   // no other write to carry it out
+    bun_pty_close(h);
+    assert!(seen);
+}
+
+#[test]
+fn shared_mode_writes_past_children_that_dont_read() {
+    let _serial = SERIAL.locked();
+    let _shared = SharedMode::on();
+    // raw, so nothing is echoed or dropped and the tty buffers simply fill up
+    let stuck: Vec<c_int> = (0..6).map(|_| unsafe {
+        bun_pty_spawn_with_options(c"sleep 30".as_ptr(), c"/tmp".as_ptr(), 80, 24, cr#"{"raw_mode": true}"#.as_ptr())
+    }).collect();
+    let junk = vec![b'x'; 1 << 20];
+    for &h in &stuck {
+        assert!(h > 0);
+        assert_eq!(unsafe { bun_pty_write(h, junk.as_ptr(), junk.len() as c_int) }, SUCCESS);
+    }
+    thread::sleep(Duration::from_millis(200));      // long enough for the pool to get stuck on them
+
+    let h = spawn("cat");
+    let line = b"got through\n";
+    assert_eq!(unsafe { bun_pty_write(h, line.as_ptr(), line.len() as c_int) }, SUCCESS);
+    let seen = read_until(h, "got through", Duration::from_secs(3));
+    for h in stuck.into_iter().chain([h]) { bun_pty_close(h); }
+    assert!(seen);
+}
+
+/// Threads in this process, from `/proc/self/status`.
+#[cfg(target_os = "linux")]
+fn thread_count() -> usize {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    status.lines().find_map(|l| l.strip_prefix("Threads:")).unwrap().trim().parse().unwrap()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn shared_mode_keeps_200_ptys_on_a_few_threads() {
+    let _serial = SERIAL.locked();
+    let _shared = SharedMode::on();
+    let before = thread_count();
+    let handles: Vec<c_int> = (0..200).map(|_| spawn("cat")).collect();
+    assert!(handles.iter().all(|&h| h > 0));
+
+    let last = *handles.last().unwrap();
+    let line = b"hello pool\n";
+    assert_eq!(unsafe { bun_pty_write(last, line.as_ptr(), line.len() as c_int) }, SUCCESS);
+    let echoed = read_until(last, "hello pool", Duration::from_secs(5));
+    let during = thread_count();
+    for &h in &handles { bun_pty_close(h); }
+
+    assert!(echoed);
+    // the poll loop and at most `POOL_SIZE` writers, where dedicated mode would need 600
+    assert!(during.saturating_sub(before) <= 1 + 4, "{before} threads before, {during} with 200 PTYs");
+}
+
+#[test]
+fn a_blocked_read_does_not_stall_another_handle() {
+    let _serial = SERIAL.locked();
+    let (quiet, busy) = (spawn("sleep 100"), spawn("cat"));
+    assert!(quiet > 0 && busy > 0);
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..2).map(|_| {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while !stop.load(Ordering::Relaxed) {   // each call sits in the handle for 500 ms
+                unsafe { bun_pty_read_timeout(quiet, buf.as_mut_ptr(), buf.len() as c_int, 500) };
+            }
+        })
+    }).collect();
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    for i in 0..20 {
+        let line = format!("line {i}\n");
+        assert_eq!(unsafe { bun_pty_write(busy, line.as_ptr(), line.len() as c_int) }, SUCCESS);
+    }
+    let echoed = read_until(busy, "line 19", Duration::from_secs(5));
+    let took = start.elapsed();
+
+    stop.store(true, Ordering::Relaxed);
+    for r in readers { r.join().unwrap(); }
+    bun_pty_close(quiet);
+    bun_pty_close(busy);
+    assert!(echoed);
+    assert!(took < Duration::from_millis(400), "writes and reads on the other handle took {took:?}");
+}
+
+#[test]
+fn locked_recovers_a_poisoned_mutex() {
+    let m = Arc::new(Mutex::new(1));
+    let m2 = m.clone();
+    let _ = thread::spawn(move || { let _g = m2.lock().unwrap(); panic!("poison it"); }).join();
+    assert!(m.is_poisoned());
+    *m.locked() += 1;
+    assert_eq!(*m.locked(), 2);
+}
+
+#[test]
+fn ffi_keeps_working_with_reg_poisoned() {
+    let _serial = SERIAL.locked();
+    let _ = thread::spawn(|| { let _g = REG.lock().unwrap(); panic!("poison REG"); }).join();
+    assert!(REG.is_poisoned());
+
+    let h = spawn("echo still here");
+    assert!(h > 0);
+    assert!(bun_pty_count() >= 1);
+    assert!(read_until(h, "still here", Duration::from_secs(5)));
+    bun_pty_close(h);
+}
+
+#[test]
+fn ffi_guard_turns_a_panic_into_the_fallback() {
+    assert_eq!(ffi_guard(ERROR, || -> c_int { panic!("inside an export") }), ERROR);
+}
+
+#[test]
+fn malformed_ffi_input_is_refused() {
+    let _serial = SERIAL.locked();
+    use std::ptr::{null, null_mut};
+    unsafe {
+        assert_eq!(bun_pty_spawn(null(), c"/tmp".as_ptr(), 80, 24), ERROR);
+        assert_eq!(bun_pty_spawn(c"sh".as_ptr(), null(), 80, 24), ERROR);
+        assert_eq!(bun_pty_spawn(c"sh".as_ptr(), c"/tmp".as_ptr(), 0, -1), ERROR);
+        assert_eq!(bun_pty_spawn(c"echo \"unterminated".as_ptr(), c"/tmp".as_ptr(), 80, 24), ERR_INVALID_CMDLINE);
+        assert_eq!(bun_pty_spawn(c"\xff\xfe".as_ptr(), c"/tmp".as_ptr(), 80, 24), ERR_NOT_FOUND);
+        assert_eq!(bun_pty_spawn_ex(null(), 1, c"/tmp".as_ptr(), 80, 24), ERROR);
+        assert_eq!(bun_pty_spawn_json(null()), ERROR);
+        assert_eq!(bun_pty_spawn_json(c"{not json".as_ptr()), ERR_INVALID_JSON);
+        assert_eq!(bun_pty_spawn_with_options(c"sh".as_ptr(), c"/tmp".as_ptr(), 80, 24, c"{\"env_blocklist\": 5}".as_ptr()), ERR_INVALID_JSON);
+        assert_eq!(bun_pty_last_error(null_mut(), 16), ERROR);
+        assert_eq!(bun_pty_list_handles(null_mut(), 4), ERROR);
+    }
+
+    let h = spawn("sleep 100");
+    let mut buf = [0u8; 16];
+    let mut n = 0;
+    unsafe {
+        assert_eq!(bun_pty_write(h, null(), 4), ERROR);
+        assert_eq!(bun_pty_write(h, buf.as_ptr(), -1), ERROR);
+        assert_eq!(bun_pty_writev(h, null(), 2), ERROR);
+        assert_eq!(bun_pty_read(h, null_mut(), 16), ERROR);
+        assert_eq!(bun_pty_read(h, buf.as_mut_ptr(), 0), ERROR);
+        assert_eq!(bun_pty_read_line(h, buf.as_mut_ptr().cast(), 2), ERROR);
+        assert_eq!(bun_pty_get_size(h, null_mut(), &mut n), ERROR);
+        assert_eq!(bun_pty_play_script(h, c"{\"steps\": 3".as_ptr()), ERR_INVALID_JSON);
+        assert_eq!(bun_pty_set_keepalive(h, 100, null(), 4), ERROR);
+    }
+    assert_eq!(bun_pty_resize(h, 0, 24), ERROR);
+    assert_eq!(bun_pty_send_break(h, -5), ERROR);
+    bun_pty_close(h);
+
+    // a closed or never-issued handle is just an error
+    for bad in [h, 0, -7, c_int::MAX] {
+        assert_eq!(unsafe { bun_pty_write(bad, buf.as_ptr(), 1) }, ERROR);
+        assert_eq!(unsafe { bun_pty_read(bad, buf.as_mut_ptr(), 16) }, ERROR);
+        assert_eq!(bun_pty_kill(bad), ERROR);
+        bun_pty_close(bad);
+    }
+}
+
+#[test]
+fn output_written_right_before_exit_is_kept() {
+    let _serial = SERIAL.locked();
+    let cmd = c"sh -c \"head -c 100000 /dev/zero | tr '\\\\0' y; exit 3\"";
+    for _ in 0..5 {
+        let h = unsafe { bun_pty_spawn_with_options(cmd.as_ptr(), c"/tmp".as_ptr(), 80, 24, c"{\"exit_drain_ms\": 50}".as_ptr()) };
+        assert!(h > 0);
+        let out = read_all(h, Duration::from_secs(10));
+        let code = bun_pty_get_exit_code(h);
+        bun_pty_close(h);
+        assert_eq!(out.iter().filter(|&&b| b == b'y').count(), 100_000);
+        assert_eq!(code, 3);
+    }
+}
+
+/* benchmarks: `cargo test --release -- --ignored --nocapture` */
+
+/// MB/s reading `head -c 64M /dev/zero` through a read-thread with `read_buffer_size`.
+fn read_throughput(read_buffer_size: usize) -> f64 {
+    const TOTAL: usize = 64 << 20;
+    let opts = std::ffi::CString::new(format!(r#"{{"read_buffer_size": {read_buffer_size}, "raw_mode": true}}"#)).unwrap();
+    let cmd = std::ffi::CString::new(format!("head -c {TOTAL} /dev/zero")).unwrap();
+    let h = unsafe { bun_pty_spawn_with_options(cmd.as_ptr(), c"/tmp".as_ptr(), 80, 24, opts.as_ptr()) };
+    assert!(h > 0);
+    let mut buf = vec![0u8; 1 << 20];
+    let (start, mut got) = (Instant::now(), 0);
+    loop {
+        match unsafe { bun_pty_read_timeout(h, buf.as_mut_ptr(), buf.len() as c_int, -1) } {
+            n if n > 0 => got += n as usize,
+            _          => break,
+        }
+    }
+    let secs = start.elapsed().as_secs_f64();
+    bun_pty_close(h);
+    assert_eq!(got, TOTAL);
+    TOTAL as f64 / secs / 1e6
+}
+
+#[test]
+#[ignore]
+fn bench_read_buffer_size() {
+    let _serial = SERIAL.locked();
+    for size in [8 << 10, 256 << 10] {
+        let runs: Vec<f64> = (0..3).map(|_| read_throughput(size)).collect();
+        println!("read_buffer_size {:>4} KB: {:.0} MB/s (best of {:.0?})", size >> 10, runs.iter().cloned().fold(0.0, f64::max), runs);
+    }
+}
+
+/// (time spent in the write calls, time until drained) for `count` writes of `len`
+/// bytes to a child discarding its input, copied or handed over.
+fn write_timing(owned: bool, len: usize, count: usize) -> (Duration, Duration) {
+    let h = unsafe { bun_pty_spawn_with_options(c"sh -c 'cat > /dev/null'".as_ptr(), c"/tmp".as_ptr(), 80, 24, c"{\"raw_mode\": true}".as_ptr()) };
+    assert!(h > 0);
+    let data = vec![b'x'; len];
+    let (start, mut in_calls) = (Instant::now(), Duration::ZERO);
+    for _ in 0..count {
+        let rc = if owned {
+            let p = bun_pty_alloc(len);
+            unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), p, len) };   // the caller filling its buffer
+            let t = Instant::now();
+            let rc = unsafe { bun_pty_write_owned(h, p, len) };
+            in_calls += t.elapsed();
+            rc
+        } else {
+            let t = Instant::now();
+            let rc = unsafe { bun_pty_write(h, data.as_ptr(), len as c_int) };
+            in_calls += t.elapsed();
+            rc
+        };
+        assert_eq!(rc, SUCCESS);
+    }
+    assert_eq!(bun_pty_drain(h, -1), SUCCESS);
+    let total = start.elapsed();
+    bun_pty_close(h);
+    (in_calls, total)
+}
+
+#[test]
+#[ignore]
+fn bench_write_owned() {
+    let _serial = SERIAL.locked();
+    for owned in [false, true] {
+        let (calls, total) = write_timing(owned, 4 << 20, 64);
+        let name = if owned { "bun_pty_write_owned" } else { "bun_pty_write" };
+        println!("{name:>20}: 64 x 4 MB, {calls:?} in the calls, {total:?} until drained");
+    }
+}
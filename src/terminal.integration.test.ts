@@ -274,3 +274,87 @@ test("Terminal can detect non-zero exit codes - cat nonexistent file", async ()
     expect(errorOutput).toContain("No such file or directory");
   }
 });
+
+test("Terminal delivers large output bursts without losing bytes", async () => {
+  let dataReceived = "";
+  let hasExited = false;
+  
+  // 64KB in one burst, while the read loop drains it in 4KB chunks
+  const terminal = new Terminal("bash", ["-c", "head -c 65536 /dev/zero | tr '\\0' 'x'"]);
+  terminals.push(terminal);
+  
+  terminal.onData((data) => {
+    dataReceived += data;
+  });
+  
+  terminal.onExit(() => {
+    hasExited = true;
+  });
+  
+  // Wait for process to exit or timeout
+  const timeout = 5000; // 5 second timeout
+  const start = Date.now();
+  
+  while (!hasExited && Date.now() - start < timeout) {
+    // Wait a bit
+    await new Promise(resolve => setTimeout(resolve, 100));
+  }
+  
+  expect(dataReceived.length).toBe(65536);
+  expect(dataReceived).toMatch(/^x+$/);
+});
+
+test.skipIf(process.platform !== "win32")("Terminal reports a Windows crash code as its signed 32-bit value", async () => {
+  let exitEvent: IExitEvent | undefined;
+
+  // STATUS_ACCESS_VIOLATION, 0xC0000005; `bun_pty_get_exit_code_u32` returns 3221225477
+  const terminal = new Terminal("cmd.exe", ["/c", "exit -1073741819"]);
+  terminals.push(terminal);
+
+  terminal.onExit((event) => {
+    exitEvent = event;
+  });
+
+  const timeout = 5000;
+  const start = Date.now();
+
+  while (!exitEvent && Date.now() - start < timeout) {
+    await new Promise(resolve => setTimeout(resolve, 100));
+  }
+
+  expect(exitEvent).toBeDefined();
+  expect(exitEvent?.exitCode).toBe(-1073741819);
+
+  expect(terminal.exitCodeU32).toBe(0xC0000005);
+});
+
+test("Terminal passes arguments with spaces, quotes and backslashes through intact", async () => {
+  // on Windows these go through the argv-to-command-line quoting; elsewhere straight to exec
+  const args = ["C:\\Program Files\\app", 'say "hi"', "trailing\\", "space and trailing\\", "a^b"];
+  const script = "console.log(JSON.stringify(process.argv.slice(-5)))";
+
+  let dataReceived = "";
+  let hasExited = false;
+
+  const terminal = new Terminal(process.execPath, ["-e", script, ...args], { name: "xterm", cols: 200 });
+  terminals.push(terminal);
+
+  terminal.onData((data) => {
+    dataReceived += data;
+  });
+
+  terminal.onExit(() => {
+    hasExited = true;
+  });
+
+  const timeout = 5000;
+  const start = Date.now();
+
+  while (!hasExited && Date.now() - start < timeout) {
+    await new Promise(resolve => setTimeout(resolve, 100));
+  }
+
+  const json = dataReceived.match(/\[".*"\]/)?.[0];
+  expect(json).toBeDefined();
+  expect(JSON.parse(json ?? "[]")).toEqual(args);
+});
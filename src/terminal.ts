@@ -127,6 +127,10 @@ try {
 		bun_pty_kill: { args: [FFIType.i32], returns: FFIType.i32 },
 		bun_pty_get_pid: { args: [FFIType.i32], returns: FFIType.i32 },
 		bun_pty_get_exit_code: { args: [FFIType.i32], returns: FFIType.i32 },
+		bun_pty_get_exit_code_u32: {
+			args: [FFIType.i32, FFIType.pointer],
+			returns: FFIType.i32,
+		},
 		bun_pty_close: { args: [FFIType.i32], returns: FFIType.void },
 	});
 } catch (error) {
@@ -201,6 +205,17 @@ export class Terminal implements IPty {
 		return this._onExit.event;
 	}
 
+	/**
+	 * The exit code as the full unsigned 32 bits the OS reported, so Windows crash
+	 * codes such as 0xC0000005 come back intact; `undefined` until the child has
+	 * exited, and after `kill`.
+	 */
+	get exitCodeU32(): number | undefined {
+		const out = new Uint32Array(1);
+		if (lib.symbols.bun_pty_get_exit_code_u32(this.handle, ptr(out)) !== 0) return undefined;
+		return out[0];
+	}
+
 	/* ------------- IO methods ------------- */
 
 	write(data: string) {